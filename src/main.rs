@@ -1,332 +1,3747 @@
-use std::sync::Arc;
+mod matcher;
+mod metrics;
+mod singleflight;
+mod svg;
+#[cfg(test)]
+mod tests;
 
-use axum::extract::{Query, State};
-use axum::http::HeaderMap;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use axum::extract::rejection::QueryRejection;
+use axum::extract::{Extension, FromRequestParts, Path, Query, State};
+use axum::http::{HeaderMap, HeaderName};
 use axum::response::IntoResponse;
-use axum::{Json, Router, routing::get};
-use futures::future::join_all;
-use futures::TryFutureExt;
-use globset::{Glob, GlobMatcher};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::future::{join_all, try_join_all};
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt, TryFutureExt};
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use moka::Expiry;
 use moka::future::Cache;
-use rayon::prelude::*;
-use reqwest::header::{ACCEPT, USER_AGENT};
-use reqwest::{StatusCode, Url};
+use reqwest::header::{
+    ACCEPT, CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LINK, RETRY_AFTER, USER_AGENT,
+};
+use reqwest::{Method, StatusCode, Url};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::request_id::{
+    MakeRequestId, PropagateRequestIdLayer, RequestId, SetRequestIdLayer,
+};
+
+use crate::matcher::{JobMatch, MatchMode, Matcher, literal};
+use crate::metrics::Metrics;
+use crate::singleflight::Singleflight;
+use tracing::{debug, info, warn};
+use tracing_subscriber::EnvFilter;
 
 #[derive(Clone)]
 struct AppState {
+    /// Swapped out whole when auto-sizing changes their capacity
+    hydra_caches: Arc<RwLock<HydraCaches>>,
+    cache_config: Arc<CacheConfig>,
+    /// Hydra hosts requested recently, which auto-sizing scales capacity by
+    hosts_seen: Cache<String, ()>,
+    /// Compiled `jobsets` and `jobs` patterns, reused across requests
+    matchers: Cache<(String, MatchMode), Matcher>,
+    client: HydraClient,
+    metrics: Metrics,
+    /// Used when a request has no `hydra_base_url`
+    default_base_url: Option<Url>,
+    /// Hosts `hydra_base_url` may point at, or any host when `None`
+    allowed_hosts: Option<Arc<HashSet<String>>>,
+    /// Overall time budget for computing a single badge
+    deadline: Duration,
+    /// Named badges loaded from `PRESETS_FILE`
+    presets: Arc<HashMap<String, RequestQuery>>,
+    /// Most builds fetched per evaluation; requests may lower but not raise it
+    max_builds: usize,
+    /// Most jobsets a request may match; requests may lower but not raise it
+    max_jobsets: usize,
+    /// Most badges a single POST /preload may ask for
+    max_preload_badges: usize,
+    /// Badges preloaded at once, by POST /preload or `WARMUP_ON_START`
+    preload_concurrency: usize,
+    /// Badge computations in flight, shared between identical requests
+    inflight: Singleflight<RequestQuery, Result<EndpointResponse, ArcEndpointError>>,
+    /// Last successful badge per request, served while Hydra is erroring
+    last_good: Cache<RequestQuery, EndpointResponse>,
+    request_log: RequestLog,
+    /// Shares project cache entries between base URLs differing only in their
+    /// query, for Hydras that ignore it
+    cache_key_ignores_query: bool,
+    /// Longest label and message in characters before they are truncated
+    max_label_length: usize,
+    max_message_length: usize,
+}
+
+/// Caches of Hydra responses, whose capacities are multiplied by `scale`
+#[derive(Clone)]
+struct HydraCaches {
     projects_cache: Cache<Url, Vec<Project>>,
     jobset_eval_list_cache: Cache<(Url, Jobset), JobsetEvalList>,
+    /// Last responses that carried an `ETag`, kept past the caches' TTLs so
+    /// refetches can be conditional
+    projects_etags: Cache<Url, Tagged<Vec<Project>>>,
+    jobset_eval_list_etags: Cache<(Url, Jobset), Tagged<JobsetEvalList>>,
+    /// Jobsets of each project with whether they're enabled
+    project_jobsets_cache: Cache<(Url, String), Vec<ProjectJobset>>,
     build_cache: Cache<(Url, i32), Build>,
+    /// Recently failed build fetches, so an outage isn't probed on every request
+    failed_build_cache: Cache<(Url, i32), EndpointError>,
+    scale: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct EndpointResponse {
-    schema_version: i8,
+impl HydraCaches {
+    fn new(caches: &CacheConfig, scale: u64) -> Self {
+        let jitter = caches.jitter;
 
-    label: String,
+        HydraCaches {
+            projects_cache: Cache::builder()
+                .max_capacity(caches.projects_capacity * scale)
+                .expire_after(JitteredTtl {
+                    ttl: caches.projects_ttl,
+                    jitter,
+                })
+                .build(),
+            jobset_eval_list_cache: Cache::builder()
+                .max_capacity(caches.jobset_evals_capacity * scale)
+                .expire_after(JitteredTtl {
+                    ttl: caches.jobset_evals_ttl,
+                    jitter,
+                })
+                .build(),
+            projects_etags: Cache::builder()
+                .max_capacity(caches.projects_capacity * scale)
+                .time_to_live(caches.etag_ttl)
+                .build(),
+            jobset_eval_list_etags: Cache::builder()
+                .max_capacity(caches.jobset_evals_capacity * scale)
+                .time_to_live(caches.etag_ttl)
+                .build(),
+            project_jobsets_cache: Cache::builder()
+                .max_capacity(caches.projects_capacity * scale)
+                .expire_after(JitteredTtl {
+                    ttl: caches.projects_ttl,
+                    jitter,
+                })
+                .build(),
+            build_cache: Cache::builder()
+                .max_capacity(caches.builds_capacity * scale)
+                .expire_after(BuildExpiry {
+                    finished: caches.finished_build_ttl,
+                    running: caches.running_build_ttl,
+                    jitter,
+                })
+                .build(),
+            failed_build_cache: Cache::builder()
+                .max_capacity(caches.failed_builds_capacity * scale)
+                .time_to_live(caches.failed_build_ttl)
+                .build(),
+            scale,
+        }
+    }
+}
 
-    message: String,
+/// Picks roughly one in every `REQUEST_LOG_SAMPLE_RATE` badge requests to log,
+/// for insight into usage without logging each one
+#[derive(Clone)]
+struct RequestLog {
+    /// Zero disables the log
+    rate: u64,
+    seen: Arc<AtomicU64>,
+}
 
-    is_error: bool,
+impl RequestLog {
+    fn sample(&self) -> bool {
+        self.rate > 0
+            && self
+                .seen
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.rate)
+    }
 }
 
-#[derive(Error, Debug, Clone, thiserror_ext::Arc)]
-#[thiserror_ext(newtype(name = ArcEndpointError))]
-enum EndpointError {
-    #[error(transparent)]
-    UrlParse(#[from] url::ParseError),
+impl AppState {
+    /// Rejects base URLs outside `ALLOWED_HYDRA_HOSTS`, preventing SSRF
+    fn check_host(&self, url: &Url) -> Result<(), EndpointError> {
+        let Some(allowed_hosts) = &self.allowed_hosts else {
+            return Ok(());
+        };
 
-    #[error(transparent)]
-    UrlParseArc(#[from] Arc<url::ParseError>),
+        match url.host_str() {
+            Some(host) if allowed_hosts.contains(host) => Ok(()),
+            host => Err(EndpointError::ForbiddenHost(
+                host.unwrap_or_default().to_string(),
+            )),
+        }
+    }
 
-    #[error(transparent)]
-    FailedReqwestArc(#[from] Arc<reqwest::Error>),
-}
+    /// Resolves a request's `hydra_base_url`, falling back to the default
+    fn base_url(&self, requested: Option<Url>) -> Result<Url, EndpointError> {
+        requested
+            .or_else(|| self.default_base_url.clone())
+            .ok_or(EndpointError::MissingBaseUrl)
+    }
 
-impl IntoResponse for EndpointError {
-    fn into_response(self) -> axum::response::Response {
-        let body = match self {
-            Self::UrlParse(error) => axum::Json(EndpointResponse {
-                is_error: true,
-                label: "URL Parse Error".into(),
-                message: error.to_string(),
-                ..Default::default()
-            }),
-            Self::UrlParseArc(error) => axum::Json(EndpointResponse {
-                is_error: true,
-                label: "URL Parse Error".into(),
-                message: error.to_string(),
-                ..Default::default()
-            }),
-            Self::FailedReqwestArc(error) => axum::Json(EndpointResponse {
-                is_error: true,
-                label: "Request Error".into(),
-                message: error.to_string(),
-                ..Default::default()
-            }),
+    /// The Hydra response caches, noting `base_url`'s host for auto-sizing
+    async fn caches(&self, base_url: &Url) -> HydraCaches {
+        if self.cache_config.autosize.is_some()
+            && let Some(host) = base_url.host_str()
+        {
+            self.hosts_seen.insert(host.to_string(), ()).await;
+        }
+
+        self.hydra_caches.read().unwrap().clone()
+    }
+
+    /// Rebuilds the Hydra response caches if the number of hosts seen has
+    /// crossed a power of two since they were last sized
+    ///
+    /// Rebuilt caches start out empty, which the power of two steps keep rare.
+    async fn autosize(&self) {
+        let Some(autosize) = &self.cache_config.autosize else {
+            return;
+        };
+
+        self.hosts_seen.run_pending_tasks().await;
+
+        let hosts = self.hosts_seen.entry_count();
+        let scale = autosize.scale(hosts);
+
+        if self.hydra_caches.read().unwrap().scale == scale {
+            return;
+        }
+
+        info!(hosts, scale, "resizing caches");
+
+        *self.hydra_caches.write().unwrap() = HydraCaches::new(&self.cache_config, scale);
+    }
+
+    /// Compiles a pattern, or reuses it if a recent request already did
+    async fn matcher(&self, pattern: &str, mode: MatchMode) -> Result<Matcher, EndpointError> {
+        Ok(self
+            .matchers
+            .try_get_with((pattern.to_string(), mode), async {
+                Matcher::new(pattern, mode)
+            })
+            .await?)
+    }
+
+    /// Compiles a request's `jobsets` and `jobs` patterns
+    async fn request_matchers(
+        &self,
+        params: &RequestQuery,
+    ) -> Result<(Matcher, Matcher), EndpointError> {
+        let mode = params.match_mode;
+        let pattern = |pattern: &Option<String>, exact| match (pattern, exact) {
+            (Some(names), true) => literal(names, mode),
+            (Some(pattern), false) => pattern.clone(),
+            (None, _) => mode.match_all().to_string(),
         };
+        let jobsets = pattern(&params.jobsets, params.exact_jobset);
+        let jobs = pattern(&params.jobs, params.exact_jobs);
+
+        Ok((
+            self.matcher(&jobsets, mode).await?,
+            self.matcher(&params.job_match.widen(&jobs, mode), mode)
+                .await?,
+        ))
+    }
+
+    /// Caps a requested `max_builds` at the server-side limit
+    fn max_builds(&self, requested: Option<usize>) -> usize {
+        requested.map_or(self.max_builds, |max| max.min(self.max_builds))
+    }
 
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+    /// Caps a requested `max_jobsets` at the server-side limit
+    fn max_jobsets(&self, requested: Option<usize>) -> usize {
+        requested.map_or(self.max_jobsets, |max| max.min(self.max_jobsets))
     }
 }
 
-impl IntoResponse for ArcEndpointError {
-    fn into_response(self) -> axum::response::Response {
-        self.inner().clone().into_response()
+/// Wraps [`reqwest::Client`] to retry transient Hydra failures
+#[derive(Clone)]
+struct HydraClient {
+    inner: reqwest::Client,
+    max_retries: u32,
+    /// Bearer tokens keyed by Hydra host
+    auth_tokens: Arc<HashMap<String, String>>,
+    /// `HYDRA_EXTRA_HEADERS` keyed by Hydra host
+    extra_headers: Arc<HashMap<String, HeaderMap>>,
+    /// Bounds concurrent build fetches across all requests
+    build_fetches: Arc<Semaphore>,
+    /// Per-host request rate limit, when `HYDRA_RATE_LIMIT_PER_SEC` is set
+    rate_limiter: Option<Arc<DefaultKeyedRateLimiter<String>>>,
+    /// How long a request may wait for the rate limiter before giving up
+    rate_limit_wait: Duration,
+    /// Upper bound on how long a `Retry-After` from Hydra can delay a retry
+    max_retry_after: Duration,
+    /// Largest response body read from Hydra, guarding against huge bodies
+    /// from misconfigured or hostile upstreams
+    max_response_bytes: usize,
+}
+
+impl HydraClient {
+    /// Builds a request, attaching the bearer token and extra headers
+    /// configured for the host
+    fn request(&self, method: Method, url: Url) -> reqwest::RequestBuilder {
+        let host = url.host_str().unwrap_or_default();
+        let token = self.auth_tokens.get(host);
+        let extra_headers = self.extra_headers.get(host).cloned();
+        let mut request = self.inner.request(method, url);
+
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+
+        if let Some(extra_headers) = extra_headers {
+            request = request.headers(extra_headers);
+        }
+
+        request
+    }
+
+    /// Waits briefly for the host's rate limit, failing if it stays exhausted
+    async fn throttle(&self, url: &Url) -> Result<(), EndpointError> {
+        let (Some(limiter), Some(host)) = (&self.rate_limiter, url.host_str()) else {
+            return Ok(());
+        };
+        let host = host.to_string();
+
+        tokio::time::timeout(self.rate_limit_wait, limiter.until_key_ready(&host))
+            .await
+            .map_err(|_| EndpointError::RateLimited(host))
+    }
+
+    /// Forgets hosts whose rate limit has fully replenished, as each host a
+    /// request names would otherwise be kept forever
+    fn prune_rate_limiter(&self) {
+        if let Some(limiter) = &self.rate_limiter {
+            limiter.retain_recent();
+            limiter.shrink_to_fit();
+        }
+    }
+
+    /// Decodes a JSON response body, first rejecting responses that declare
+    /// some other content type, like a reverse proxy's HTML error page
+    async fn json<T: serde::de::DeserializeOwned>(
+        &self,
+        mut response: reqwest::Response,
+    ) -> Result<T, EndpointError> {
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+
+        if let Some(content_type) = content_type
+            && !content_type.contains("json")
+        {
+            return Err(EndpointError::NonJson(content_type.to_string()));
+        }
+
+        let limit = self.max_response_bytes;
+        let too_large = EndpointError::ResponseTooLarge(limit);
+
+        if response
+            .content_length()
+            .is_some_and(|length| length > limit as u64)
+        {
+            return Err(too_large);
+        }
+
+        // Read chunk by chunk, as `Content-Length` may be missing or wrong
+        let mut body = Vec::new();
+
+        while let Some(chunk) = response.chunk().await.map_err(Arc::new)? {
+            if body.len() + chunk.len() > limit {
+                return Err(too_large);
+            }
+
+            body.extend_from_slice(&chunk);
+        }
+
+        serde_json::from_slice(&body).map_err(|error| EndpointError::InvalidJson(error.to_string()))
+    }
+
+    /// GETs `url`, retrying 429 and 5xx responses and connection errors with
+    /// exponential backoff, or after Hydra's `Retry-After` when it sends one
+    async fn get(&self, url: Url) -> Result<reqwest::Response, EndpointError> {
+        let (response, _) = self.send_with_retries(url, None, None).await?;
+
+        Ok(response)
+    }
+
+    /// Like [`HydraClient::get`], but conditional on `etag` when given
+    async fn get_if_none_match(
+        &self,
+        url: Url,
+        etag: Option<&str>,
+    ) -> Result<reqwest::Response, EndpointError> {
+        let (response, _) = self.send_with_retries(url, etag, None).await?;
+
+        Ok(response)
+    }
+
+    /// Like [`HydraClient::get`], but holding one of `build_fetches` for each
+    /// attempt, and not while waiting to retry or for the rate limit, so a slow
+    /// Hydra doesn't hold up build fetches from the others
+    ///
+    /// The permit is returned with the response, to be held while its body is
+    /// read.
+    async fn get_build(
+        &self,
+        url: Url,
+    ) -> Result<(reqwest::Response, SemaphorePermit<'_>), EndpointError> {
+        let (response, permit) = self
+            .send_with_retries(url, None, Some(&self.build_fetches))
+            .await?;
+
+        Ok((
+            response,
+            permit.expect("build fetches always take a permit"),
+        ))
+    }
+
+    async fn send_with_retries<'a>(
+        &self,
+        url: Url,
+        etag: Option<&str>,
+        permits: Option<&'a Semaphore>,
+    ) -> Result<(reqwest::Response, Option<SemaphorePermit<'a>>), EndpointError> {
+        let mut attempt = 0;
+
+        loop {
+            self.throttle(&url).await?;
+
+            let mut request = self.request(Method::GET, url.clone());
+
+            if let Some(etag) = etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+
+            let permit = match permits {
+                Some(permits) => Some(
+                    permits
+                        .acquire()
+                        .await
+                        .expect("build fetch semaphore is never closed"),
+                ),
+                None => None,
+            };
+            let result = request.send().await;
+
+            let retryable = match &result {
+                Ok(response) => {
+                    response.status() == StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error()
+                }
+                Err(error) => error.is_connect(),
+            };
+
+            if !retryable || attempt >= self.max_retries {
+                return match result {
+                    Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                        Err(EndpointError::UpstreamRateLimited(
+                            url.host_str().unwrap_or_default().into(),
+                        ))
+                    }
+                    result => result
+                        .map(|response| (response, permit))
+                        .map_err(|error| Arc::new(error).into()),
+                };
+            }
+
+            drop(permit);
+
+            let backoff = Duration::from_millis(100 << attempt.min(10));
+            let delay = match &result {
+                Ok(response) => retry_after(response.headers())
+                    .map_or(backoff, |delay| delay.min(self.max_retry_after)),
+                Err(_) => backoff,
+            };
+
+            debug!(%url, attempt, ?delay, "retrying Hydra request");
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct RequestQuery {
-    hydra_base_url: Url,
-    jobsets: Glob,
-    jobs: Glob,
+/// Parses a `Retry-After` given in seconds, as Hydra and most proxies send it
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+
+    Some(Duration::from_secs(seconds))
 }
 
-/// Returned in a list from GET hydra_base_url
-#[derive(Clone, Serialize, Deserialize, Debug)]
-struct Project {
-    name: String,
-    jobsets: Vec<String>,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct EndpointResponse {
+    schema_version: SchemaVersion,
+
+    label: String,
+
+    message: String,
+
+    is_error: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_seconds: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    named_logo: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logo_color: Option<String>,
+
+    /// State behind the badge, for the plain text format
+    #[serde(skip)]
+    state: Option<JobsetState>,
+
+    /// How long each phase took, for requests with `debug` set
+    #[serde(skip)]
+    timings: Timings,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-struct JobsetEvaluation {
-    builds: Vec<i32>,
+fn truncate(text: &mut String, max: usize) {
+    if max == 0 || text.chars().count() <= max {
+        return;
+    }
+
+    *text = text.chars().take(max - 1).chain(['…']).collect();
 }
 
-/// Returned from GET jobset/:project/:jobset/evals
-#[derive(Deserialize, Debug, Clone)]
-struct JobsetEvalList {
-    evals: Vec<JobsetEvaluation>,
+/// Durations of the phases of computing a badge, in the order they ran
+#[derive(Clone, Debug, Default)]
+struct Timings(Vec<(&'static str, Duration)>);
+
+impl Timings {
+    fn record(&mut self, phase: &'static str, started: Instant) {
+        self.0.push((phase, started.elapsed()));
+    }
+
+    /// Formats the phases as a `Server-Timing` header value, in milliseconds
+    fn server_timing(&self) -> String {
+        self.0
+            .iter()
+            .map(|(phase, duration)| format!("{phase};dur={:.3}", duration.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
 
-/// Returned from GET build/:id
-#[derive(Deserialize, Clone, Debug)]
-struct Build {
-    job: String,
-    finished: i32,
-    buildstatus: i32,
+/// Endpoint schema versions understood by shields.io, serialized as integers
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(into = "u8", try_from = "u8")]
+enum SchemaVersion {
+    #[default]
+    V1,
 }
 
-impl Default for EndpointResponse {
-    fn default() -> Self {
-        EndpointResponse {
-            schema_version: 1,
-            is_error: false,
-            label: "Default Label".into(),
-            message: "Default Message".into(),
+impl From<SchemaVersion> for u8 {
+    fn from(version: SchemaVersion) -> Self {
+        match version {
+            SchemaVersion::V1 => 1,
         }
     }
 }
 
-#[derive(Clone, Hash, Eq, PartialEq, Debug)]
-struct Jobset {
-    project: String,
-    name: String,
-}
+impl TryFrom<u8> for SchemaVersion {
+    type Error = String;
 
-impl ToString for Jobset {
-    fn to_string(&self) -> String {
-        format!("{}:{}", self.project, self.name)
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(Self::V1),
+            other => Err(format!("unsupported schemaVersion {other}")),
+        }
     }
 }
 
-fn headers() -> HeaderMap {
-    let mut headers = HeaderMap::new();
+#[derive(Error, Debug, Clone, thiserror_ext::Arc)]
+#[thiserror_ext(newtype(name = ArcEndpointError))]
+enum EndpointError {
+    #[error(transparent)]
+    UrlParse(#[from] url::ParseError),
 
-    headers.insert(ACCEPT, "application/json".parse().unwrap());
-    headers.insert(USER_AGENT, "hydra-shields-endpoint".parse().unwrap());
+    #[error(transparent)]
+    UrlParseArc(#[from] Arc<url::ParseError>),
 
-    headers
-}
+    #[error(transparent)]
+    FailedReqwestArc(Arc<reqwest::Error>),
 
-async fn fetch_jobset_eval_list(
-    client: reqwest::Client,
-    base_url: Url,
-    jobset: Jobset,
-) -> Result<JobsetEvalList, EndpointError> {
-    let url = base_url.join(&format!("jobset/{}/{}/evals", jobset.project, jobset.name))?;
+    #[error("Hydra did not respond in time")]
+    Timeout,
 
-    let evals = client
-        .get(url)
-        .headers(headers())
-        .send()
-        .await.map_err(Arc::new)?
-        .json::<JobsetEvalList>()
-        .await.map_err(Arc::new)?;
+    #[error("Hydra returned {code} for {url}")]
+    UpstreamStatus { code: u16, url: String },
 
-    Ok(evals)
-}
+    #[error("{0}")]
+    InvalidPattern(String),
 
-async fn fetch_build(
-    client: reqwest::Client,
-    base_url: Url,
-    build: i32,
-) -> Result<Build, EndpointError> {
-    let url = base_url.join(&format!("build/{}", build))?;
+    #[error("{0:?} is not an allowed Hydra host")]
+    ForbiddenHost(String),
 
-    let build = client
-        .get(url)
-        .headers(headers())
-        .send()
-        .await.map_err(Arc::new)?
-        .json::<Build>()
-        .await.map_err(Arc::new)?;
+    #[error("timeout")]
+    DeadlineExceeded,
 
-    Ok(build)
-}
+    #[error("no preset named {0:?}")]
+    UnknownPreset(String),
 
-async fn check_jobset_evaluation(
-    client: reqwest::Client,
-    base_url: Url,
-    job_matcher: GlobMatcher,
-    evaluation: &JobsetEvaluation,
-    build_cache: Cache<(Url, i32), Build>
-) -> Result<(bool, bool), EndpointError> {
-    let statuses = evaluation
-        .builds
-        .par_iter()
-        .map(|build| {
-            build_cache.try_get_with((base_url.clone(), *build), {
-                fetch_build(client.clone(), base_url.clone(), *build)
-            }).map_err(|x| Arc::into_inner(x).unwrap())
-        })
-        .collect::<Vec<_>>();
+    #[error("too many requests to {0}")]
+    RateLimited(String),
 
-    let statuses = join_all(statuses)
-        .await
-        .into_par_iter()
-        .collect::<Result<Vec<_>, EndpointError>>()?;
-    let filtered = statuses
-        .par_iter()
-        .filter(|build| job_matcher.is_match(build.job.clone()))
-        .collect::<Vec<_>>();
+    #[error("rate limited by Hydra at {0}")]
+    UpstreamRateLimited(String),
 
-    if filtered.is_empty() {
-        return Ok((false, true));
-    }
+    #[error("no hydra_base_url given and no default configured")]
+    MissingBaseUrl,
+
+    #[error("Hydra returned non-JSON response (content-type: {0})")]
+    NonJson(String),
 
-    let queued = filtered.par_iter().any(|x| x.finished != 1);
-    let failure = filtered.par_iter().any(|x| x.buildstatus != 0);
+    #[error("Hydra returned invalid JSON: {0}")]
+    InvalidJson(String),
 
-    Ok((queued, failure))
+    #[error("Hydra response exceeded {0} bytes")]
+    ResponseTooLarge(usize),
+
+    #[error("{0}")]
+    InvalidQuery(String),
+
+    #[error("too many jobsets matched ({matched}, max {max}), narrow your glob")]
+    TooManyJobsets { matched: usize, max: usize },
 }
 
-async fn check_list_passing(
-    client: reqwest::Client,
-    base_url: Url,
-    job_matcher: GlobMatcher,
-    list: &JobsetEvalList,
-    cache: Cache<(Url, i32), Build>
-) -> Result<bool, EndpointError> {
-    for evaluation in &list.evals {
-        let (queued, failure) = check_jobset_evaluation(
-            client.clone(),
-            base_url.clone(),
-            job_matcher.clone(),
-            evaluation,
-            cache.clone()
+impl EndpointError {
+    /// Variant name used to label error metrics
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::UrlParse(_) | Self::UrlParseArc(_) => "url_parse",
+            Self::FailedReqwestArc(_) => "request",
+            Self::Timeout => "timeout",
+            Self::UpstreamStatus { .. } => "upstream_status",
+            Self::InvalidPattern(_) => "invalid_pattern",
+            Self::ForbiddenHost(_) => "forbidden_host",
+            Self::DeadlineExceeded => "deadline_exceeded",
+            Self::UnknownPreset(_) => "unknown_preset",
+            Self::RateLimited(_) => "rate_limited",
+            Self::UpstreamRateLimited(_) => "upstream_rate_limited",
+            Self::MissingBaseUrl => "missing_base_url",
+            Self::NonJson(_) => "non_json",
+            Self::InvalidJson(_) => "invalid_json",
+            Self::ResponseTooLarge(_) => "response_too_large",
+            Self::TooManyJobsets { .. } => "too_many_jobsets",
+            Self::InvalidQuery(_) => "invalid_query",
+        }
+    }
+
+    /// Whether the failure lies with Hydra rather than the request
+    fn is_upstream(&self) -> bool {
+        matches!(
+            self,
+            Self::FailedReqwestArc(_)
+                | Self::Timeout
+                | Self::UpstreamStatus { .. }
+                | Self::RateLimited(_)
+                | Self::UpstreamRateLimited(_)
+                | Self::NonJson(_)
+                | Self::InvalidJson(_)
+                | Self::ResponseTooLarge(_)
         )
-        .await?;
+    }
+
+    /// Badge label naming the kind of failure
+    fn label(&self) -> &'static str {
+        match self {
+            Self::UrlParse(_) | Self::UrlParseArc(_) => "URL Parse Error",
+            Self::FailedReqwestArc(_) => "Request Error",
+            Self::Timeout => "Timeout",
+            Self::UpstreamStatus { .. }
+            | Self::NonJson(_)
+            | Self::InvalidJson(_)
+            | Self::ResponseTooLarge(_) => "Upstream Error",
+            Self::InvalidPattern(_) | Self::TooManyJobsets { .. } => "Pattern Error",
+            Self::InvalidQuery(_) => "Invalid Query",
+            Self::ForbiddenHost(_) => "Forbidden Host",
+            Self::DeadlineExceeded => "Deadline Exceeded",
+            Self::UnknownPreset(_) => "Unknown Preset",
+            Self::RateLimited(_) | Self::UpstreamRateLimited(_) => "Rate Limited",
+            Self::MissingBaseUrl => "Missing Base URL",
+        }
+    }
 
-        if queued { continue; }
+    /// Error badge describing this error, the single source for how every
+    /// error is rendered
+    fn to_response_body(&self) -> EndpointResponse {
+        warn!(error = %self, "responding with error badge");
 
-        return Ok(!failure);
+        EndpointResponse {
+            is_error: true,
+            label: self.label().into(),
+            message: self.to_string(),
+            ..Default::default()
+        }
     }
+}
 
-    Ok(false)
+/// Errors from moka's `try_get_with` are shared between every caller waiting on
+/// the same key, so the inner error has to be cloned rather than unwrapped
+impl From<Arc<EndpointError>> for EndpointError {
+    fn from(error: Arc<EndpointError>) -> Self {
+        (*error).clone()
+    }
 }
 
-#[axum::debug_handler]
-async fn endpoint(
-    Query(params): Query<RequestQuery>,
-    State(state): State<AppState>,
-) -> Result<Json<EndpointResponse>, ArcEndpointError> {
-    let client = reqwest::Client::new();
-    let jobset_matcher = params.jobsets.compile_matcher();
-    let job_matcher = params.jobs.compile_matcher();
-
-    let projects = state.projects_cache.try_get_with(params.hydra_base_url.clone(), async {
-        client
-            .get(params.hydra_base_url.clone())
-            .headers(headers())
-            .send()
-            .await?
-            .json::<Vec<Project>>()
-            .await
-    }).await?;
+impl From<Arc<reqwest::Error>> for EndpointError {
+    fn from(error: Arc<reqwest::Error>) -> Self {
+        if error.is_timeout() {
+            return Self::Timeout;
+        }
 
-    let jobsets = projects
-        .par_iter()
-        .flat_map(|project| {
-            project.jobsets.par_iter().map(|jobset| Jobset {
-                project: project.name.clone(),
-                name: jobset.to_string(),
-            })
-        })
-        .filter(|x| jobset_matcher.is_match(x.to_string()))
-        .map(|jobset|  {
-            let url = params.hydra_base_url.clone();
-            let client = client.clone();
-
-            state.jobset_eval_list_cache.try_get_with((url.clone(), jobset.clone()), async move {
-                fetch_jobset_eval_list(client.clone(), url.clone(), jobset.clone()).await
-            }).map_err(|x| Arc::into_inner(x).unwrap())
-        })
-        .collect::<Vec<_>>();
+        Self::FailedReqwestArc(error)
+    }
+}
 
-    let jobset_eval_lists: Vec<JobsetEvalList> = join_all(jobsets)
-        .await
-        .into_par_iter()
-        .collect::<Result<_, EndpointError>>()?;
+impl From<QueryRejection> for EndpointError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::InvalidQuery(rejection.body_text())
+    }
+}
 
-    let passing = jobset_eval_lists.iter().map(|list| {
-        check_list_passing(
-            client.clone(),
-            params.hydra_base_url.clone(),
-            job_matcher.clone(),
-            list,
-            state.build_cache.clone()
-        )
-    }).collect::<Vec<_>>();
+/// Like [`Query`], but a malformed query string is answered with an error
+/// badge rather than a plain text 400 that shields can't display
+///
+/// Badge handlers take a `Result` of it and pass the rejection on to
+/// [`respond`], so it's counted, tagged and truncated like any other error.
+#[derive(FromRequestParts)]
+#[from_request(via(Query), rejection(EndpointError))]
+struct BadgeQuery<T>(T);
 
-    let jobset_eval_lists: Vec<bool> = join_all(passing)
-        .await
-        .into_par_iter()
-        .collect::<Result<_, EndpointError>>()?;
+impl IntoResponse for EndpointError {
+    fn into_response(self) -> axum::response::Response {
+        error_response(&self)
+    }
+}
 
-    if jobset_eval_lists.iter().all(|bool| *bool) {
-        return Ok(axum::Json(EndpointResponse {
-            label: format!("{}:{}", params.jobsets, params.jobs),
-            message: "passing".into(),
-            ..Default::default()
-        }));
+impl IntoResponse for ArcEndpointError {
+    fn into_response(self) -> axum::response::Response {
+        error_response(self.inner())
     }
+}
 
-    Ok(axum::Json(EndpointResponse {
-        label: format!("{}:{}", params.jobsets, params.jobs),
-        message: "one or more jobs failing".into(),
-        is_error: true,
-        ..Default::default()
-    }))
+/// Renders an error badge, shared by both error types' `IntoResponse` impls
+fn error_response(error: &EndpointError) -> axum::response::Response {
+    // Shields only renders our message for successful responses; the failure
+    // is conveyed by `isError` instead. It also rejects anything not labelled
+    // JSON, so the content type is spelled out rather than left to `Json`
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, "application/json")],
+        Json(error.to_response_body()),
+    )
+        .into_response()
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+struct RequestQuery {
+    /// Falls back to `DEFAULT_HYDRA_BASE_URL`
+    #[serde(default, deserialize_with = "base_url")]
+    hydra_base_url: Option<Url>,
+    /// Comma separated globs, or a single regex in regex mode; both match
+    /// everything when omitted
+    jobsets: Option<String>,
+    jobs: Option<String>,
+    #[serde(default)]
+    match_mode: MatchMode,
+    /// Whether `jobs` has to match whole job names, or just their start or
+    /// any part
+    #[serde(default)]
+    job_match: JobMatch,
+    /// Matches each of `jobsets` literally, see [`literal`]
+    #[serde(default)]
+    exact_jobset: bool,
+    /// Matches each of `jobs` literally, see [`literal`]
+    #[serde(default)]
+    exact_jobs: bool,
+    #[serde(default = "default_cache_seconds")]
+    cache_seconds: u32,
+    label: Option<String>,
+    #[serde(default)]
+    eval_strategy: EvalStrategy,
+    /// Only looks at this many of the newest evaluations, or all when unset
+    #[serde(default)]
+    eval_window: Option<usize>,
+    #[serde(default)]
+    metric: Metric,
+    /// Treat failing jobsets as passing and vice versa
+    #[serde(default)]
+    invert: bool,
+    #[serde(default)]
+    aggregate: Aggregate,
+    #[serde(default)]
+    source: Source,
+    /// Checks at most this many of the most recent builds per evaluation
+    max_builds: Option<usize>,
+    /// Fails rather than checking more than this many matched jobsets
+    max_jobsets: Option<usize>,
+    /// Also matches jobsets disabled in Hydra, or in disabled projects
+    #[serde(default)]
+    include_disabled: bool,
+    /// Reports "no data" until at least this many matched builds finished
+    #[serde(default)]
+    min_finished_builds: usize,
+    /// Largest share of failed builds that still counts as passing
+    #[serde(default)]
+    max_fail_ratio: FailRatio,
+    /// Appends how long ago the underlying Hydra data was fetched
+    #[serde(default)]
+    show_age: bool,
+    /// Names the failing jobs instead of counting passing builds
+    #[serde(default)]
+    show_failing: bool,
+    /// Treats builds whose dependencies failed as skipped rather than failed
+    #[serde(default)]
+    ignore_dep_failures: bool,
+    /// Skips cache reads, refreshing cached Hydra responses
+    #[serde(default)]
+    no_cache: bool,
+    /// Reports how long each phase took in a `Server-Timing` header
+    #[serde(default)]
+    debug: bool,
+    #[serde(default)]
+    format: Format,
+    /// shields.io `namedLogo`, e.g. "nixos"
+    logo: Option<String>,
+    logo_color: Option<String>,
+    /// Checks exactly this jobset instead of matching `jobsets` against the
+    /// project list
+    #[serde(skip)]
+    only_jobset: Option<Jobset>,
+}
 
-    let state = AppState {
-        projects_cache: Cache::new(100),
-        jobset_eval_list_cache: Cache::new(100),
-        build_cache: Cache::new(1000)
-    };
+impl RequestQuery {
+    /// The `jobsets` pattern, or the match-all pattern when omitted
+    fn jobsets(&self) -> &str {
+        self.jobsets
+            .as_deref()
+            .unwrap_or(self.match_mode.match_all())
+    }
 
-    let app = Router::new().route("/", get(endpoint)).with_state(state);
+    /// The `jobs` pattern, or the match-all pattern when omitted
+    fn jobs(&self) -> &str {
+        self.jobs.as_deref().unwrap_or(self.match_mode.match_all())
+    }
+}
+
+/// Query for GET /jobset/:project/:jobset
+#[derive(Deserialize, Debug)]
+struct JobsetQuery {
+    /// Falls back to `DEFAULT_HYDRA_BASE_URL`
+    #[serde(default, deserialize_with = "base_url")]
+    hydra_base_url: Option<Url>,
+    #[serde(default = "default_cache_seconds")]
+    cache_seconds: u32,
+    label: Option<String>,
+    #[serde(default)]
+    eval_strategy: EvalStrategy,
+    #[serde(default)]
+    eval_window: Option<usize>,
+    #[serde(default)]
+    source: Source,
+    max_builds: Option<usize>,
+    #[serde(default)]
+    min_finished_builds: usize,
+    #[serde(default)]
+    max_fail_ratio: FailRatio,
+    #[serde(default)]
+    show_age: bool,
+    #[serde(default)]
+    show_failing: bool,
+    #[serde(default)]
+    ignore_dep_failures: bool,
+    #[serde(default)]
+    no_cache: bool,
+    #[serde(default)]
+    format: Format,
+    logo: Option<String>,
+    logo_color: Option<String>,
+}
+
+/// Query for GET /build/:id
+#[derive(Deserialize, Debug)]
+struct BuildQuery {
+    /// Falls back to `DEFAULT_HYDRA_BASE_URL`
+    #[serde(default, deserialize_with = "base_url")]
+    hydra_base_url: Option<Url>,
+    #[serde(default = "default_cache_seconds")]
+    cache_seconds: u32,
+    /// Defaults to the build's job name
+    label: Option<String>,
+    #[serde(default)]
+    no_cache: bool,
+    #[serde(default)]
+    format: Format,
+    logo: Option<String>,
+    logo_color: Option<String>,
+}
+
+/// Which evaluations of a jobset are considered when deciding its state
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+enum EvalStrategy {
+    /// Only the newest evaluation, even if it is still queued
+    Latest,
+    /// The newest evaluation whose matched builds have all finished
+    #[default]
+    AnyRecent,
+}
+
+/// How the states of several matched jobsets are combined into one badge
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Aggregate {
+    /// Counts builds across every matched jobset
+    #[default]
+    All,
+    /// Reports only the worst matched jobset, by name
+    Worst,
+}
+
+/// What a badge reports on
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+enum Metric {
+    /// Whether the matched builds pass
+    #[default]
+    Status,
+    /// How many matched builds in each jobset's newest evaluation are still
+    /// queued or running
+    QueueDepth,
+}
+
+/// Where a jobset's state is read from
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Source {
+    /// Fetches every build in the chosen evaluation, honouring `jobs`
+    #[default]
+    Builds,
+    /// Reads Hydra's per-jobset counters, ignoring `jobs` and `eval_strategy`
+    Counters,
+}
+
+/// A ratio of failed to matched builds, between 0 and 1
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(try_from = "f64")]
+struct FailRatio(f64);
+
+impl TryFrom<f64> for FailRatio {
+    type Error = String;
+
+    fn try_from(ratio: f64) -> Result<Self, Self::Error> {
+        match ratio {
+            0.0..=1.0 => Ok(FailRatio(ratio)),
+            _ => Err(format!("max_fail_ratio {ratio} is not between 0 and 1")),
+        }
+    }
+}
+
+// Compared bitwise so that equality agrees with `Hash`, letting requests be
+// keyed by their parameters
+impl PartialEq for FailRatio {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for FailRatio {}
+
+impl std::hash::Hash for FailRatio {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state);
+    }
+}
+
+/// How the computed badge is returned
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    /// shields.io endpoint JSON
+    #[default]
+    Json,
+    /// A badge rendered by us, for embedding without shields.io
+    Svg,
+    /// Just the state, e.g. "passing", for uptime checkers
+    Text,
+}
+
+/// Ensures a base URL ends in `/`, as otherwise [`Url::join`] replaces its last
+/// path segment instead of appending to it
+fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+
+    url
+}
+
+/// Normalizes a base URL for use as a cache key, so equivalent URLs share
+/// entries. The fragment never reaches Hydra, so is always dropped
+fn cache_key(url: &Url, ignore_query: bool) -> Url {
+    let mut key = url.clone();
+    key.set_fragment(None);
+
+    if ignore_query {
+        key.set_query(None);
+    }
+
+    key
+}
+
+/// Deserializes a `hydra_base_url`, normalized with [`with_trailing_slash`]
+fn base_url<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Url>, D::Error> {
+    Option::<Url>::deserialize(deserializer).map(|url| url.map(with_trailing_slash))
+}
+
+fn default_cache_seconds() -> u32 {
+    300
+}
+
+/// Returned in a list from GET hydra_base_url
+#[derive(Clone, Serialize, Deserialize, Debug)]
+struct Project {
+    name: String,
+    #[serde(default)]
+    jobsets: Vec<String>,
+    /// Missing on older Hydras, whose projects are all treated as enabled
+    #[serde(default = "enabled", deserialize_with = "flag")]
+    enabled: bool,
+}
+
+/// Returned in a list from GET api/jobsets?project=:project
+#[derive(Clone, Deserialize, Debug)]
+struct ProjectJobset {
+    name: String,
+    #[serde(default = "enabled", deserialize_with = "flag")]
+    enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// Deserializes a Hydra boolean, which some versions send as `0` or `1`.
+/// Jobsets also use `2` and `3` for one-shot and one-at-a-time evaluation,
+/// both of which count as enabled
+fn flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Int(i64),
+    }
+
+    Ok(match Flag::deserialize(deserializer)? {
+        Flag::Bool(flag) => flag,
+        Flag::Int(flag) => flag != 0,
+    })
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct JobsetEvaluation {
+    #[serde(default)]
+    id: i32,
+    /// Unix time the evaluation was created
+    #[serde(default)]
+    timestamp: i64,
+    #[serde(default)]
+    builds: Vec<i32>,
+    /// Set when Hydra failed to evaluate the jobset's expression
+    #[serde(default)]
+    evaluationerror: Option<EvaluationError>,
+}
+
+impl JobsetEvaluation {
+    fn has_error(&self) -> bool {
+        self.evaluationerror
+            .as_ref()
+            .is_some_and(|error| !error.errormsg.is_empty())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct EvaluationError {
+    #[serde(default)]
+    errormsg: String,
+}
+
+/// A Hydra response alongside the `ETag` it was served with
+#[derive(Clone, Debug)]
+struct Tagged<T> {
+    etag: String,
+    value: T,
+}
+
+/// Reads the `ETag` header of a response, if any
+fn etag(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(ETAG)?
+        .to_str()
+        .ok()
+        .map(|etag| etag.to_string())
+}
+
+/// Returned from GET jobset/:project/:jobset/evals
+#[derive(Deserialize, Debug, Clone)]
+struct JobsetEvalList {
+    #[serde(default)]
+    evals: Vec<JobsetEvaluation>,
+    /// When this list was fetched or last revalidated, for `show_age`
+    #[serde(skip, default = "Instant::now")]
+    fetched_at: Instant,
+}
+
+/// Returned from GET project/:project/jobset/:jobset on Hydra versions that
+/// expose build counters
+#[derive(Deserialize, Debug, Clone, Copy)]
+struct JobsetCounters {
+    #[serde(default)]
+    nrsucceeded: usize,
+    #[serde(default)]
+    nrfailed: usize,
+    #[serde(default)]
+    nrtotal: usize,
+}
+
+impl JobsetCounters {
+    /// Builds neither succeeded nor failed are treated as still queued
+    fn status(&self, min_finished: usize, max_fail_ratio: FailRatio) -> JobsetStatus {
+        // `nrtotal` is missing on some versions, so it's at least the finished builds
+        let total = self.nrtotal.max(self.nrsucceeded + self.nrfailed);
+
+        JobsetStatus {
+            insufficient: self.nrsucceeded + self.nrfailed < min_finished,
+            max_fail_ratio,
+            counts: BuildCounts {
+                total,
+                passing: self.nrsucceeded,
+                failed: self.nrfailed,
+                skipped: 0,
+                queued: total - (self.nrsucceeded + self.nrfailed),
+                sampled: 0,
+                unsampled: 0,
+                unreachable: 0,
+                failing_jobs: Vec::new(),
+            },
+            building: false,
+            fetched_at: None,
+            evaluation: None,
+            eval_error: false,
+        }
+    }
+}
+
+/// Returned from GET build/:id
+#[derive(Deserialize, Clone, Debug)]
+struct Build {
+    job: String,
+    /// Missing on some Hydra versions, in which case the build counts as queued
+    #[serde(default)]
+    finished: i32,
+    /// Absent or null until the build has finished
+    #[serde(default)]
+    buildstatus: Option<BuildStatus>,
+}
+
+/// Hydra's integer `buildstatus`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(from = "i32", into = "i32")]
+enum BuildStatus {
+    Success,
+    Failed,
+    DepFailed,
+    Aborted,
+    Cancelled,
+    TimedOut,
+    Other(i32),
+}
+
+impl From<i32> for BuildStatus {
+    fn from(status: i32) -> Self {
+        match status {
+            0 => Self::Success,
+            // 6 is "failed with output"
+            1 | 6 => Self::Failed,
+            2 => Self::DepFailed,
+            3 => Self::Aborted,
+            4 => Self::Cancelled,
+            7 => Self::TimedOut,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// Serialized as the code, so GET /debug/status reads against GET /legend
+impl From<BuildStatus> for i32 {
+    fn from(status: BuildStatus) -> Self {
+        match status {
+            BuildStatus::Success => 0,
+            BuildStatus::Failed => 1,
+            BuildStatus::DepFailed => 2,
+            BuildStatus::Aborted => 3,
+            BuildStatus::Cancelled => 4,
+            BuildStatus::TimedOut => 7,
+            BuildStatus::Other(other) => other,
+        }
+    }
+}
+
+impl BuildStatus {
+    /// Codes with a variant of their own, for GET /legend
+    const KNOWN: [i32; 7] = [0, 1, 2, 3, 4, 6, 7];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Success => "succeeded",
+            Self::Failed => "failed",
+            Self::DepFailed => "dependency failed",
+            Self::Aborted => "aborted",
+            Self::Cancelled => "cancelled",
+            Self::TimedOut => "timed out",
+            Self::Other(_) => "other",
+        }
+    }
+
+    /// Builds that never ran to completion for reasons unrelated to the job
+    /// itself, which shouldn't turn a badge red
+    fn is_skipped(self) -> bool {
+        matches!(self, Self::Aborted | Self::Cancelled)
+    }
+}
+
+/// Shortens `ttl` by a random share of up to `jitter`, so entries cached
+/// together don't all expire, and get refetched, at the same instant
+fn jittered(ttl: Duration, jitter: f64) -> Duration {
+    ttl.mul_f64(1.0 - jitter.clamp(0.0, 1.0) * fastrand::f64())
+}
+
+/// A time to live with [`jittered`] applied per entry
+struct JitteredTtl {
+    ttl: Duration,
+    jitter: f64,
+}
+
+impl<K, V> Expiry<K, V> for JitteredTtl {
+    fn expire_after_create(&self, _key: &K, _value: &V, _created_at: Instant) -> Option<Duration> {
+        Some(jittered(self.ttl, self.jitter))
+    }
+
+    /// Restarts the TTL on refresh, like moka's own `time_to_live`
+    fn expire_after_update(
+        &self,
+        key: &K,
+        value: &V,
+        updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        self.expire_after_create(key, value, updated_at)
+    }
+}
+
+/// Keeps finished builds cached for much longer than running ones, so a queued
+/// build is re-fetched promptly once it completes
+struct BuildExpiry {
+    finished: Duration,
+    running: Duration,
+    jitter: f64,
+}
+
+impl Expiry<(Url, i32), Build> for BuildExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &(Url, i32),
+        build: &Build,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        let ttl = match build.finished {
+            1 => self.finished,
+            _ => self.running,
+        };
+
+        Some(jittered(ttl, self.jitter))
+    }
+
+    /// A refreshed build may have finished since it was first cached
+    fn expire_after_update(
+        &self,
+        key: &(Url, i32),
+        build: &Build,
+        updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        self.expire_after_create(key, build, updated_at)
+    }
+}
+
+impl EndpointResponse {
+    /// Shortens the label and message to at most this many characters, ending
+    /// them with an ellipsis. Zero leaves them as they are
+    fn truncate(&mut self, max_label: usize, max_message: usize) {
+        truncate(&mut self.label, max_label);
+        truncate(&mut self.message, max_message);
+    }
+
+    fn render(self, format: Format) -> axum::response::Response {
+        match format {
+            Format::Json => ([(CONTENT_TYPE, "application/json")], Json(self)).into_response(),
+            Format::Svg => {
+                let color = self.color.as_deref().unwrap_or(match self.is_error {
+                    true => "red",
+                    false => "lightgrey",
+                });
+                let body = svg::render(&self.label, &self.message, color);
+                let cache_control = match self.cache_seconds {
+                    Some(seconds) => format!("max-age={seconds}"),
+                    None => "no-cache".into(),
+                };
+
+                (
+                    [
+                        (CONTENT_TYPE, "image/svg+xml".to_string()),
+                        (CACHE_CONTROL, cache_control),
+                    ],
+                    body,
+                )
+                    .into_response()
+            }
+            Format::Text => {
+                let status = match (self.state, self.is_error) {
+                    (Some(state), _) => state.as_str(),
+                    (None, true) => "error",
+                    (None, false) => "unknown",
+                };
+
+                ([(CONTENT_TYPE, "text/plain; charset=utf-8")], status).into_response()
+            }
+        }
+    }
+}
+
+impl Default for EndpointResponse {
+    fn default() -> Self {
+        EndpointResponse {
+            schema_version: SchemaVersion::V1,
+            is_error: false,
+            label: "Default Label".into(),
+            message: "Default Message".into(),
+            color: None,
+            cache_seconds: None,
+            named_logo: None,
+            logo_color: None,
+            state: None,
+            timings: Timings::default(),
+        }
+    }
+}
+
+/// Matched builds in the evaluation chosen for a jobset
+#[derive(Serialize, Clone, Default, Debug)]
+struct BuildCounts {
+    total: usize,
+    passing: usize,
+    failed: usize,
+    /// Cancelled or aborted, counted neither as passing nor failing
+    skipped: usize,
+    queued: usize,
+    /// Builds fetched from the evaluation, matched or not
+    sampled: usize,
+    /// Builds in the evaluation left unfetched because of `max_builds`
+    unsampled: usize,
+    /// Builds whose fetch failed, left out of every other count
+    unreachable: usize,
+    /// Jobs of the failed builds, sorted
+    failing_jobs: Vec<String>,
+}
+
+impl BuildCounts {
+    fn finished(&self) -> usize {
+        self.passing + self.failed + self.skipped
+    }
+
+    fn fail_ratio(&self) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => self.failed as f64 / total as f64,
+        }
+    }
+
+    fn is_passing(&self) -> bool {
+        self.passing > 0 && self.passing + self.skipped == self.total
+    }
+
+    /// Every matched build was skipped, so none says whether the jobs pass
+    fn all_skipped(&self) -> bool {
+        self.total > 0 && self.skipped == self.total
+    }
+}
+
+/// Outcome of checking a single jobset
+#[derive(Clone, Default, Debug)]
+struct JobsetStatus {
+    counts: BuildCounts,
+    /// A newer evaluation than the one counted still has running builds
+    building: bool,
+    /// Fewer matched builds have finished than `min_finished_builds`
+    insufficient: bool,
+    max_fail_ratio: FailRatio,
+    /// When the evaluation list behind this status was fetched, if cached
+    fetched_at: Option<Instant>,
+    /// ID of the evaluation the counts came from
+    evaluation: Option<i32>,
+    /// The evaluation failed, so there are no builds to count
+    eval_error: bool,
+}
+
+impl JobsetStatus {
+    /// Some builds failed, but few enough to stay under `max_fail_ratio`
+    fn tolerated(&self) -> bool {
+        self.counts.failed > 0 && self.counts.fail_ratio() <= self.max_fail_ratio.0
+    }
+
+    /// None of the builds of a real evaluation matched `jobs`, as opposed to
+    /// having no evaluation or too few builds to judge, which are "no data"
+    fn matched_nothing(&self) -> bool {
+        self.evaluation.is_some()
+            && self.counts.total == 0
+            && self.counts.unreachable == 0
+            && !self.insufficient
+            && !self.eval_error
+            && !self.building
+    }
+
+    fn state(&self) -> JobsetState {
+        let building = self.building || self.counts.queued > 0;
+
+        if self.eval_error {
+            JobsetState::EvalError
+        } else if self.insufficient {
+            match building {
+                true => JobsetState::Building,
+                false => JobsetState::NoData,
+            }
+        } else if self.counts.failed > 0 && !self.tolerated() {
+            JobsetState::Failing
+        } else if building {
+            JobsetState::Building
+        } else if self.counts.is_passing() || self.tolerated() {
+            JobsetState::Passing
+        } else if self.counts.all_skipped() {
+            JobsetState::NoData
+        } else {
+            JobsetState::Failing
+        }
+    }
+}
+
+/// Badge state, ordered from best to worst
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+enum JobsetState {
+    Passing,
+    /// Too few finished builds to call passing or failing
+    NoData,
+    Building,
+    Failing,
+    /// The jobset's expression failed to evaluate
+    EvalError,
+}
+
+impl JobsetState {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Passing => "passing",
+            Self::NoData => "no data",
+            Self::Building => "building",
+            Self::Failing => "failing",
+            Self::EvalError => "eval error",
+        }
+    }
+}
+
+#[derive(Clone, Hash, Eq, PartialEq, Ord, PartialOrd, Debug)]
+struct Jobset {
+    project: String,
+    name: String,
+}
+
+impl std::fmt::Display for Jobset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.project, self.name)
+    }
+}
+
+/// Most failing jobs named by `show_failing`, to keep the badge readable
+const MAX_FAILING_JOBS: usize = 3;
+
+/// Names the first few failing jobs, e.g. "failing: foo, bar (+3 more)"
+fn failing_message(jobs: &[&String]) -> String {
+    let named = jobs
+        .iter()
+        .take(MAX_FAILING_JOBS)
+        .map(|job| job.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    match jobs.len().saturating_sub(MAX_FAILING_JOBS) {
+        0 => format!("failing: {named}"),
+        more => format!("failing: {named} (+{more} more)"),
+    }
+}
+
+/// Formats a duration in its largest whole unit, e.g. "30s" or "5m"
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        seconds @ 0..60 => format!("{seconds}s"),
+        seconds @ 60..3600 => format!("{}m", seconds / 60),
+        seconds @ 3600..86_400 => format!("{}h", seconds / 3600),
+        seconds => format!("{}d", seconds / 86_400),
+    }
+}
+
+/// Maps a badge state to a shields.io color
+fn badge_color(state: JobsetState) -> String {
+    match state {
+        JobsetState::Passing => "brightgreen",
+        JobsetState::NoData => "lightgrey",
+        JobsetState::Building => "yellow",
+        JobsetState::Failing | JobsetState::EvalError => "red",
+    }
+    .into()
+}
+
+/// Reads and parses an environment variable, falling back to `default` when it
+/// is unset or invalid
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            warn!("invalid value {value:?} for {name}, using default");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Parses `HYDRA_AUTH_TOKEN`, a comma separated list of `host=token` pairs
+fn auth_tokens() -> HashMap<String, String> {
+    let Ok(value) = std::env::var("HYDRA_AUTH_TOKEN") else {
+        return HashMap::new();
+    };
+
+    value
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.split_once('=') {
+            Some((host, token)) => Some((host.trim().to_string(), token.trim().to_string())),
+            None => {
+                warn!("ignoring HYDRA_AUTH_TOKEN entry without a host");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Builds the per-host limiter from `HYDRA_RATE_LIMIT_PER_SEC` and
+/// `HYDRA_RATE_LIMIT_BURST`, or `None` when no rate is configured
+fn rate_limiter() -> Option<DefaultKeyedRateLimiter<String>> {
+    let per_second = NonZeroU32::new(env_or("HYDRA_RATE_LIMIT_PER_SEC", 0))?;
+    let burst = NonZeroU32::new(env_or("HYDRA_RATE_LIMIT_BURST", 0)).unwrap_or(per_second);
+
+    info!("limiting Hydra requests to {per_second}/s per host, bursting to {burst}");
+
+    Some(RateLimiter::keyed(
+        Quota::per_second(per_second).allow_burst(burst),
+    ))
+}
+
+/// Badge for a request whose globs matched nothing, so typos never look green
+fn no_matches(label: String, message: &str, params: &RequestQuery) -> EndpointResponse {
+    EndpointResponse {
+        label,
+        message: message.into(),
+        is_error: true,
+        color: Some("red".into()),
+        cache_seconds: Some(params.cache_seconds),
+        named_logo: params.logo.clone(),
+        logo_color: params.logo_color.clone(),
+        ..Default::default()
+    }
+}
+
+/// Builds the HTTP client used for Hydra, trusting any extra roots in
+/// `HYDRA_CA_BUNDLE`
+fn http_client(
+    user_agent: &str,
+    allowed_hosts: Option<Arc<HashSet<String>>>,
+    extra_header_hosts: HashSet<String>,
+) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .default_headers(headers(user_agent)?)
+        .redirect(redirect_policy(allowed_hosts, extra_header_hosts))
+        .connect_timeout(Duration::from_millis(env_or(
+            "HYDRA_CONNECT_TIMEOUT_MS",
+            5_000,
+        )))
+        .timeout(Duration::from_millis(env_or(
+            "HYDRA_REQUEST_TIMEOUT_MS",
+            30_000,
+        )))
+        // Defaults match reqwest's: unbounded idle connections per host, each
+        // closed after 90s unused. Lower the timeout below Hydra's keep-alive
+        // timeout if requests fail on connections the server already closed
+        .pool_max_idle_per_host(env_or("HYDRA_POOL_MAX_IDLE_PER_HOST", usize::MAX))
+        .pool_idle_timeout(Duration::from_secs(env_or(
+            "HYDRA_POOL_IDLE_TIMEOUT_SECS",
+            90,
+        )));
+
+    if let Ok(path) = std::env::var("HYDRA_CA_BUNDLE") {
+        let pem = std::fs::read(&path)
+            .with_context(|| format!("failed to read HYDRA_CA_BUNDLE {path:?}"))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("failed to parse HYDRA_CA_BUNDLE {path:?}"))?;
+
+        info!("trusting {} extra root certificates", certificates.len());
+
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    // HTTP/2 multiplexes concurrent build fetches over a single connection.
+    // `auto` only uses it when Hydra offers it over TLS, while `http2` assumes
+    // it even over plain HTTP
+    builder = match std::env::var("HYDRA_HTTP_VERSION").as_deref() {
+        Err(_) | Ok("auto") => builder,
+        Ok("http1") => builder.http1_only(),
+        Ok("http2") => builder.http2_prior_knowledge(),
+        Ok(version) => {
+            anyhow::bail!("invalid HYDRA_HTTP_VERSION {version:?}, expected auto, http1 or http2")
+        }
+    };
+
+    if env_or("HYDRA_DANGER_ACCEPT_INVALID_CERTS", false) {
+        warn!("accepting invalid Hydra TLS certificates, do not use in production");
+
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder.build().context("failed to build HTTP client")
+}
+
+/// Follows redirects like reqwest's default, but only to `ALLOWED_HYDRA_HOSTS`
+/// when set, so an allowed Hydra can't redirect requests to internal hosts
+///
+/// Requests to `extra_header_hosts` never leave their host, as reqwest would
+/// carry `HYDRA_EXTRA_HEADERS` along to wherever they're redirected.
+fn redirect_policy(
+    allowed_hosts: Option<Arc<HashSet<String>>>,
+    extra_header_hosts: HashSet<String>,
+) -> reqwest::redirect::Policy {
+    const MAX_REDIRECTS: usize = 10;
+
+    reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+
+        let from = attempt.previous().first().and_then(Url::host_str);
+
+        if let Some(from) = from
+            && extra_header_hosts.contains(from)
+            && attempt.url().host_str() != Some(from)
+        {
+            let error = EndpointError::ForbiddenHost(
+                attempt.url().host_str().unwrap_or_default().to_string(),
+            );
+            return attempt.error(error);
+        }
+
+        match (&allowed_hosts, attempt.url().host_str()) {
+            (None, _) => attempt.follow(),
+            (Some(allowed_hosts), Some(host)) if allowed_hosts.contains(host) => attempt.follow(),
+            (Some(_), host) => {
+                let error = EndpointError::ForbiddenHost(host.unwrap_or_default().to_string());
+                attempt.error(error)
+            }
+        }
+    })
+}
+
+fn headers(user_agent: &str) -> anyhow::Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+
+    headers.insert(ACCEPT, "application/json".parse().unwrap());
+    headers.insert(
+        USER_AGENT,
+        user_agent
+            .parse()
+            .with_context(|| format!("invalid HYDRA_USER_AGENT {user_agent:?}"))?,
+    );
+
+    Ok(headers)
+}
+
+/// Parses `HYDRA_EXTRA_HEADERS`, one `host|Name: value` per line, for Hydras
+/// behind proxies that gate access on headers
+///
+/// Headers are only sent to their host, like `HYDRA_AUTH_TOKEN`, as they are
+/// often secrets. Lines rather than commas separate headers, as commas are
+/// common in values.
+fn extra_headers() -> anyhow::Result<HashMap<String, HeaderMap>> {
+    let mut headers = HashMap::<String, HeaderMap>::new();
+
+    let Ok(value) = std::env::var("HYDRA_EXTRA_HEADERS") else {
+        return Ok(headers);
+    };
+
+    for line in value.lines().filter(|line| !line.trim().is_empty()) {
+        let (host, header) = line
+            .split_once('|')
+            .with_context(|| format!("HYDRA_EXTRA_HEADERS line {line:?} has no host"))?;
+        let (name, value) = header
+            .split_once(':')
+            .with_context(|| format!("HYDRA_EXTRA_HEADERS line {line:?} has no ':'"))?;
+        let name = name
+            .trim()
+            .parse::<HeaderName>()
+            .with_context(|| format!("invalid header name in HYDRA_EXTRA_HEADERS {name:?}"))?;
+        let value = value
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid value in HYDRA_EXTRA_HEADERS for {name}"))?;
+
+        headers
+            .entry(host.trim().to_string())
+            .or_default()
+            .append(name, value);
+    }
+
+    info!("sending extra headers to {} Hydra hosts", headers.len());
+
+    Ok(headers)
+}
+
+/// Converts a failed `error_for_status` into an error naming the Hydra path
+fn upstream_error(error: reqwest::Error, path: &str) -> EndpointError {
+    match error.status() {
+        Some(status) => EndpointError::UpstreamStatus {
+            code: status.as_u16(),
+            url: path.into(),
+        },
+        None => Arc::new(error).into(),
+    }
+}
+
+/// Upper bound on followed `Link: rel="next"` pages, in case Hydra loops
+const MAX_PROJECT_PAGES: usize = 100;
+
+/// Fetches every project, following `Link: rel="next"` pagination if present
+///
+/// The first page is requested conditionally on the last known `ETag`. Only
+/// unpaginated responses are tagged, since the first page's `ETag` says
+/// nothing about later pages.
+#[tracing::instrument(skip(client, metrics, etags), fields(%base_url), err(Display))]
+async fn fetch_projects(
+    client: HydraClient,
+    base_url: Url,
+    metrics: Metrics,
+    etags: Cache<Url, Tagged<Vec<Project>>>,
+) -> Result<Vec<Project>, EndpointError> {
+    let _timer = metrics
+        .fetch_duration
+        .with_label_values(&["projects"])
+        .start_timer();
+
+    let previous = etags.get(&base_url).await;
+    let mut projects = Vec::new();
+    let mut next = Some(base_url.clone());
+    let mut tag = None;
+
+    for page in 0..MAX_PROJECT_PAGES {
+        let Some(url) = next.take() else {
+            break;
+        };
+
+        let if_none_match = match page {
+            0 => previous.as_ref().map(|previous| previous.etag.as_str()),
+            _ => None,
+        };
+
+        let response = client
+            .get_if_none_match(url.clone(), if_none_match)
+            .await?
+            .error_for_status()
+            .map_err(|error| upstream_error(error, url.as_str()))?;
+
+        if let Some(previous) = &previous
+            && response.status() == StatusCode::NOT_MODIFIED
+        {
+            debug!(%url, "projects not modified, reusing previous response");
+
+            return Ok(previous.value.clone());
+        }
+
+        next = next_page(&url, response.headers());
+
+        if page == 0 && next.is_none() {
+            tag = etag(response.headers());
+        }
+
+        let page = client.json::<Vec<Project>>(response).await?;
+
+        debug!(%url, projects = page.len(), "fetched project page");
+
+        projects.extend(page);
+    }
+
+    if let Some(etag) = tag {
+        etags
+            .insert(
+                base_url,
+                Tagged {
+                    etag,
+                    value: projects.clone(),
+                },
+            )
+            .await;
+    }
+
+    Ok(projects)
+}
+
+/// Resolves the `rel="next"` target of a `Link` header against `url`
+///
+/// Targets on another origin are ignored, so a response can't steer requests
+/// past `ALLOWED_HYDRA_HOSTS`.
+fn next_page(url: &Url, headers: &HeaderMap) -> Option<Url> {
+    let link = headers.get(LINK)?.to_str().ok()?;
+
+    link.split(',').find_map(|entry| {
+        let (target, params) = entry.split_once(';')?;
+        let is_next = params.split(';').any(|param| {
+            param.trim().strip_prefix("rel=").is_some_and(|rel| {
+                rel.trim_matches('"')
+                    .split_whitespace()
+                    .any(|rel| rel == "next")
+            })
+        });
+
+        if !is_next {
+            return None;
+        }
+
+        let target = target.trim().strip_prefix('<')?.strip_suffix('>')?;
+        let next = url.join(target).ok()?;
+
+        if next.origin() != url.origin() {
+            warn!(%url, %next, "ignoring next page on another origin");
+            return None;
+        }
+
+        Some(next)
+    })
+}
+
+#[tracing::instrument(skip(client, metrics, etags), fields(%base_url, %jobset), err(Display))]
+async fn fetch_jobset_eval_list(
+    client: HydraClient,
+    base_url: Url,
+    jobset: Jobset,
+    metrics: Metrics,
+    etags: Cache<(Url, Jobset), Tagged<JobsetEvalList>>,
+) -> Result<JobsetEvalList, EndpointError> {
+    let path = format!("jobset/{}/{}/evals", jobset.project, jobset.name);
+    let url = base_url.join(&path)?;
+    let key = (base_url, jobset);
+    let _timer = metrics
+        .fetch_duration
+        .with_label_values(&["jobset_evals"])
+        .start_timer();
+
+    let previous = etags.get(&key).await;
+
+    let response = client
+        .get_if_none_match(
+            url.clone(),
+            previous.as_ref().map(|previous| previous.etag.as_str()),
+        )
+        .await?
+        .error_for_status()
+        .map_err(|error| upstream_error(error, &path))?;
+
+    if let Some(previous) = previous
+        && response.status() == StatusCode::NOT_MODIFIED
+    {
+        debug!(%url, "jobset evaluations not modified, reusing previous response");
+
+        return Ok(JobsetEvalList {
+            fetched_at: Instant::now(),
+            ..previous.value
+        });
+    }
+
+    let tag = etag(response.headers());
+    let mut evals = client.json::<JobsetEvalList>(response).await?;
+
+    // Newest first, rather than trusting Hydra's ordering
+    evals
+        .evals
+        .sort_by_key(|eval| std::cmp::Reverse((eval.timestamp, eval.id)));
+
+    debug!(%url, evals = evals.evals.len(), "fetched jobset evaluations");
+
+    if let Some(etag) = tag {
+        etags
+            .insert(
+                key,
+                Tagged {
+                    etag,
+                    value: evals.clone(),
+                },
+            )
+            .await;
+    }
+
+    Ok(evals)
+}
+
+#[tracing::instrument(skip(client, metrics), fields(%base_url, %jobset), err(Display))]
+async fn fetch_jobset(
+    client: HydraClient,
+    base_url: Url,
+    jobset: Jobset,
+    metrics: Metrics,
+) -> Result<JobsetCounters, EndpointError> {
+    let path = format!("project/{}/jobset/{}", jobset.project, jobset.name);
+    let url = base_url.join(&path)?;
+    let _timer = metrics
+        .fetch_duration
+        .with_label_values(&["jobset"])
+        .start_timer();
+
+    let response = client
+        .get(url.clone())
+        .await?
+        .error_for_status()
+        .map_err(|error| upstream_error(error, &path))?;
+    let counters = client.json::<JobsetCounters>(response).await?;
+
+    debug!(%url, ?counters, "fetched jobset counters");
+
+    Ok(counters)
+}
+
+/// Lists a project's jobsets, which unlike the project list says whether each
+/// one is enabled
+async fn fetch_project_jobsets(
+    client: HydraClient,
+    base_url: Url,
+    project: String,
+    metrics: Metrics,
+) -> Result<Vec<ProjectJobset>, EndpointError> {
+    let mut url = base_url.join("api/jobsets")?;
+    url.query_pairs_mut().append_pair("project", &project);
+    let _timer = metrics
+        .fetch_duration
+        .with_label_values(&["project_jobsets"])
+        .start_timer();
+
+    let response = client.get(url.clone()).await?;
+
+    // Cached as an empty listing, so older Hydras aren't asked on every request
+    if response.status() == StatusCode::NOT_FOUND {
+        debug!(%url, "jobset listing unsupported, treating every jobset as enabled");
+
+        return Ok(Vec::new());
+    }
+
+    let response = response
+        .error_for_status()
+        .map_err(|error| upstream_error(error, url.as_str()))?;
+    let jobsets = client.json::<Vec<ProjectJobset>>(response).await?;
+
+    debug!(%url, jobsets = jobsets.len(), "fetched project jobsets");
+
+    Ok(jobsets)
+}
+
+#[tracing::instrument(skip(client, metrics), fields(%base_url), err(Display))]
+async fn fetch_build(
+    client: HydraClient,
+    base_url: Url,
+    build: i32,
+    metrics: Metrics,
+) -> Result<Build, EndpointError> {
+    let path = format!("build/{}", build);
+    let url = base_url.join(&path)?;
+    let _timer = metrics
+        .fetch_duration
+        .with_label_values(&["build"])
+        .start_timer();
+
+    let (response, _permit) = client.get_build(url.clone()).await?;
+    let response = response
+        .error_for_status()
+        .map_err(|error| upstream_error(error, &path))?;
+    let build = client.json::<Build>(response).await?;
+
+    debug!(%url, job = build.job, finished = build.finished, buildstatus = ?build.buildstatus, "fetched build");
+
+    Ok(build)
+}
+
+/// Reads `key` from `cache`, initializing it with `fetch` on a miss. With
+/// `refresh` the read is skipped and the entry replaced by a fresh value.
+async fn get_or_fetch<K, V>(
+    cache: &Cache<K, V>,
+    key: K,
+    refresh: bool,
+    fetch: impl Future<Output = Result<V, EndpointError>>,
+) -> Result<V, EndpointError>
+where
+    K: std::hash::Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    if !refresh {
+        return Ok(cache.try_get_with(key, fetch).await?);
+    }
+
+    let value = fetch.await?;
+    cache.insert(key, value.clone()).await;
+
+    Ok(value)
+}
+
+/// Looks up a build in the cache, fetching it on a miss unless the same fetch
+/// failed recently
+async fn get_build(
+    state: &AppState,
+    base_url: Url,
+    build: i32,
+    refresh: bool,
+) -> Result<Build, EndpointError> {
+    let key = (base_url.clone(), build);
+    let caches = state.caches(&base_url).await;
+
+    if !refresh && let Some(error) = caches.failed_build_cache.get(&key).await {
+        debug!(%base_url, build, "reusing recent build fetch failure");
+        return Err(error);
+    }
+
+    state.metrics.cache_lookup("build");
+
+    let result = get_or_fetch(&caches.build_cache, key.clone(), refresh, async {
+        state.metrics.cache_miss("build");
+
+        fetch_build(
+            state.client.clone(),
+            base_url.clone(),
+            build,
+            state.metrics.clone(),
+        )
+        .await
+    })
+    .await;
+
+    if let Err(error) = &result {
+        caches.failed_build_cache.insert(key, error.clone()).await;
+    }
+
+    result
+}
+
+/// The newest `max_builds` builds of an evaluation
+fn sampled_builds(evaluation: &JobsetEvaluation, max_builds: usize) -> Vec<i32> {
+    // Build IDs increase over time, so the highest are the most recent
+    let mut builds = evaluation.builds.clone();
+    builds.sort_unstable_by(|a, b| b.cmp(a));
+    builds.truncate(max_builds);
+
+    builds
+}
+
+async fn check_jobset_evaluation(
+    state: &AppState,
+    base_url: Url,
+    job_matcher: Matcher,
+    evaluation: &JobsetEvaluation,
+    max_builds: usize,
+    ignore_dep_failures: bool,
+    refresh: bool,
+) -> Result<BuildCounts, EndpointError> {
+    let builds = sampled_builds(evaluation, max_builds);
+
+    let results = builds
+        .iter()
+        .map(|build| get_build(state, base_url.clone(), *build, refresh))
+        .collect::<FuturesUnordered<_>>()
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut statuses = Vec::with_capacity(results.len());
+    let mut errors = Vec::new();
+
+    for result in results {
+        match result {
+            Ok(build) => statuses.push(build),
+            Err(error) => errors.push(error),
+        }
+    }
+
+    let unreachable = errors.len();
+
+    // Report on the builds we could fetch, unless there are none to report on
+    if statuses.is_empty()
+        && let Some(error) = errors.into_iter().next()
+    {
+        return Err(error);
+    }
+
+    if unreachable > 0 {
+        warn!(%base_url, unreachable, "some builds could not be fetched");
+    }
+
+    let is_skipped = |status: BuildStatus| {
+        status.is_skipped() || (ignore_dep_failures && status == BuildStatus::DepFailed)
+    };
+
+    let mut counts = BuildCounts {
+        sampled: builds.len(),
+        unsampled: evaluation.builds.len() - builds.len(),
+        unreachable,
+        ..Default::default()
+    };
+
+    for build in statuses
+        .iter()
+        .filter(|build| job_matcher.is_match(&build.job))
+    {
+        counts.total += 1;
+
+        if build.finished != 1 {
+            counts.queued += 1;
+            continue;
+        }
+
+        match build.buildstatus {
+            Some(BuildStatus::Success) => counts.passing += 1,
+            Some(status) if is_skipped(status) => counts.skipped += 1,
+            // Finished without a status, so it can't be called passing
+            _ => counts.failing_jobs.push(build.job.clone()),
+        }
+    }
+
+    counts.failing_jobs.sort_unstable();
+    counts.failed = counts.failing_jobs.len();
+
+    Ok(counts)
+}
+
+async fn check_list_passing(
+    state: &AppState,
+    params: &RequestQuery,
+    base_url: Url,
+    job_matcher: Matcher,
+    list: &JobsetEvalList,
+) -> Result<JobsetStatus, EndpointError> {
+    let min_finished = params.min_finished_builds;
+    let mut building = false;
+
+    let window = params.eval_window.unwrap_or(list.evals.len());
+
+    for evaluation in list.evals.iter().take(window) {
+        if evaluation.has_error() {
+            return Ok(JobsetStatus {
+                max_fail_ratio: params.max_fail_ratio,
+                evaluation: Some(evaluation.id),
+                eval_error: true,
+                ..Default::default()
+            });
+        }
+
+        let counts = check_jobset_evaluation(
+            state,
+            base_url.clone(),
+            job_matcher.clone(),
+            evaluation,
+            state.max_builds(params.max_builds),
+            params.ignore_dep_failures,
+            params.no_cache,
+        )
+        .await?;
+
+        if counts.queued > 0
+            && counts.failed == 0
+            && params.eval_strategy == EvalStrategy::AnyRecent
+        {
+            building = true;
+            continue;
+        }
+
+        return Ok(JobsetStatus {
+            insufficient: counts.finished() < min_finished,
+            counts,
+            building,
+            max_fail_ratio: params.max_fail_ratio,
+            fetched_at: None,
+            evaluation: Some(evaluation.id),
+            eval_error: false,
+        });
+    }
+
+    Ok(JobsetStatus {
+        building,
+        insufficient: min_finished > 0,
+        max_fail_ratio: params.max_fail_ratio,
+        ..Default::default()
+    })
+}
+
+#[axum::debug_handler]
+async fn endpoint(
+    query: Result<BadgeQuery<RequestQuery>, EndpointError>,
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+    headers: HeaderMap,
+) -> axum::response::Response {
+    let mut params = match query {
+        Ok(BadgeQuery(params)) => params,
+        Err(error) => return respond_error(&state, &request_id, error).await,
+    };
+
+    if prefers_text(&headers) {
+        params.format = Format::Text;
+    }
+
+    serve_badge(params, state, &request_id).await
+}
+
+/// Whether the client asked for plain text rather than JSON, as simple uptime
+/// checkers do
+fn prefers_text(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(ACCEPT).and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+
+    accept.contains("text/plain") && !accept.contains("application/json")
+}
+
+#[axum::debug_handler]
+async fn preset(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+) -> axum::response::Response {
+    let Some(params) = state.presets.get(&name).cloned() else {
+        return respond_error(&state, &request_id, EndpointError::UnknownPreset(name)).await;
+    };
+
+    serve_badge(params, state, &request_id).await
+}
+
+#[axum::debug_handler]
+async fn jobset(
+    Path((project, name)): Path<(String, String)>,
+    query: Result<BadgeQuery<JobsetQuery>, EndpointError>,
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+) -> axum::response::Response {
+    let query = match query {
+        Ok(BadgeQuery(query)) => query,
+        Err(error) => return respond_error(&state, &request_id, error).await,
+    };
+    let jobset = Jobset { project, name };
+
+    let params = RequestQuery {
+        hydra_base_url: query.hydra_base_url,
+        jobsets: Some(globset::escape(&jobset.to_string())),
+        jobs: None,
+        match_mode: MatchMode::Glob,
+        job_match: JobMatch::Exact,
+        exact_jobset: false,
+        exact_jobs: false,
+        cache_seconds: query.cache_seconds,
+        label: Some(query.label.unwrap_or_else(|| jobset.to_string())),
+        eval_strategy: query.eval_strategy,
+        eval_window: query.eval_window,
+        metric: Metric::Status,
+        invert: false,
+        aggregate: Aggregate::All,
+        source: query.source,
+        max_builds: query.max_builds,
+        max_jobsets: None,
+        include_disabled: false,
+        min_finished_builds: query.min_finished_builds,
+        max_fail_ratio: query.max_fail_ratio,
+        show_age: query.show_age,
+        show_failing: query.show_failing,
+        ignore_dep_failures: query.ignore_dep_failures,
+        no_cache: query.no_cache,
+        debug: false,
+        format: query.format,
+        logo: query.logo,
+        logo_color: query.logo_color,
+        only_jobset: Some(jobset),
+    };
+
+    serve_badge(params, state, &request_id).await
+}
+
+#[axum::debug_handler]
+async fn build(
+    Path(id): Path<i32>,
+    query: Result<BadgeQuery<BuildQuery>, EndpointError>,
+    State(state): State<AppState>,
+    Extension(request_id): Extension<RequestId>,
+) -> axum::response::Response {
+    let query = match query {
+        Ok(BadgeQuery(query)) => query,
+        Err(error) => return respond_error(&state, &request_id, error).await,
+    };
+    let format = query.format;
+    let badge = build_badge(id, query, state.clone());
+
+    respond(&state, &request_id, format, None, badge).await
+}
+
+#[tracing::instrument(skip(query, state), fields(hydra_base_url = ?query.hydra_base_url))]
+async fn build_badge(
+    id: i32,
+    query: BuildQuery,
+    state: AppState,
+) -> Result<EndpointResponse, ArcEndpointError> {
+    let base_url = state.base_url(query.hydra_base_url.clone())?;
+    state.check_host(&base_url)?;
+
+    let build = get_build(&state, base_url, id, query.no_cache).await?;
+
+    let (message, state, color) = match (build.finished, build.buildstatus) {
+        (1, Some(BuildStatus::Success)) => (
+            "success",
+            JobsetState::Passing,
+            badge_color(JobsetState::Passing),
+        ),
+        (1, Some(status)) if status.is_skipped() => (
+            "cancelled",
+            JobsetState::NoData,
+            badge_color(JobsetState::NoData),
+        ),
+        (1, _) => (
+            "failed",
+            JobsetState::Failing,
+            badge_color(JobsetState::Failing),
+        ),
+        _ => (
+            "building",
+            JobsetState::Building,
+            badge_color(JobsetState::Building),
+        ),
+    };
+
+    debug!(job = build.job, ?state, "decided build badge state");
+
+    Ok(EndpointResponse {
+        label: query.label.unwrap_or(build.job),
+        message: message.into(),
+        is_error: state == JobsetState::Failing,
+        color: Some(color),
+        cache_seconds: Some(query.cache_seconds),
+        named_logo: query.logo,
+        logo_color: query.logo_color,
+        state: Some(state),
+        ..Default::default()
+    })
+}
+
+/// Computes a badge within the configured deadline, recording metrics
+#[tracing::instrument(
+    skip_all,
+    fields(
+        hydra_base_url = ?params.hydra_base_url,
+        jobsets = params.jobsets(),
+        jobs = params.jobs(),
+    )
+)]
+async fn serve_badge(
+    params: RequestQuery,
+    state: AppState,
+    request_id: &RequestId,
+) -> axum::response::Response {
+    let format = params.format;
+    let computation = state.inflight.run(
+        params.clone(),
+        badge_or_stale(params.clone(), state.clone()),
+    );
+
+    respond(&state, request_id, format, Some(&params), computation).await
+}
+
+/// Computes a badge, falling back to the last good one for the same request
+/// when Hydra fails, so a brief outage doesn't flip dashboards to errors
+async fn badge_or_stale(
+    params: RequestQuery,
+    state: AppState,
+) -> Result<EndpointResponse, ArcEndpointError> {
+    match badge(params.clone(), state.clone()).await {
+        Ok(badge) => {
+            state.last_good.insert(params, badge.clone()).await;
+            Ok(badge)
+        }
+        Err(error) if error.inner().is_upstream() => match state.last_good.get(&params).await {
+            Some(mut stale) => {
+                warn!(%error, "serving stale badge");
+
+                stale.message.push_str(" (stale)");
+                stale.timings = Timings::default();
+                Ok(stale)
+            }
+            None => Err(error),
+        },
+        Err(error) => Err(error),
+    }
+}
+
+/// Runs `badge` within the configured deadline, recording metrics and
+/// rendering any error as an error badge tagged with the request ID
+#[tracing::instrument(
+    skip_all,
+    fields(request_id = request_id.header_value().to_str().unwrap_or_default())
+)]
+async fn respond(
+    state: &AppState,
+    request_id: &RequestId,
+    format: Format,
+    params: Option<&RequestQuery>,
+    badge: impl Future<Output = Result<EndpointResponse, ArcEndpointError>>,
+) -> axum::response::Response {
+    state.metrics.requests.inc();
+
+    let started = Instant::now();
+
+    // Dropping the timed out future abandons any outstanding Hydra fetches
+    let result = tokio::time::timeout(state.deadline, badge)
+        .await
+        .unwrap_or_else(|_| Err(EndpointError::DeadlineExceeded.into()));
+
+    if let Some(params) = params
+        && state.request_log.sample()
+    {
+        info!(
+            host = params
+                .hydra_base_url
+                .as_ref()
+                .or(state.default_base_url.as_ref())
+                .and_then(Url::host_str),
+            jobsets = params.jobsets(),
+            jobs = params.jobs(),
+            outcome = result
+                .as_ref()
+                .map_or_else(|error| error.inner().kind(), |_| "ok"),
+            duration_ms = started.elapsed().as_millis() as u64,
+            "sampled badge request"
+        );
+    }
+
+    if let Err(error) = &result {
+        state
+            .metrics
+            .errors
+            .with_label_values(&[error.inner().kind()])
+            .inc();
+    }
+
+    let (mut badge, is_error) = match result {
+        Ok(badge) => (badge, false),
+        Err(error) => (error.inner().to_response_body(), true),
+    };
+
+    badge.truncate(state.max_label_length, state.max_message_length);
+
+    // Added after truncating, so the ID survives however long the error is
+    if is_error {
+        let request_id = request_id.header_value().to_str().unwrap_or_default();
+
+        badge.message = format!("{} [req={request_id}]", badge.message);
+    }
+
+    let mut timings = std::mem::take(&mut badge.timings);
+    let mut response = badge.render(format);
+
+    if params.is_some_and(|params| params.debug) {
+        timings.record("total", started);
+
+        if let Ok(value) = timings.server_timing().parse() {
+            response.headers_mut().insert("server-timing", value);
+        }
+    }
+
+    response
+}
+
+/// Answers with an error badge found before there was a badge to compute
+async fn respond_error(
+    state: &AppState,
+    request_id: &RequestId,
+    error: EndpointError,
+) -> axum::response::Response {
+    let error = error.into();
+
+    respond(state, request_id, Format::Json, None, async { Err(error) }).await
+}
+
+/// Looks up a jobset's evaluations in the cache, fetching them on a miss
+async fn get_jobset_eval_list(
+    state: &AppState,
+    base_url: &Url,
+    jobset: Jobset,
+    refresh: bool,
+) -> Result<JobsetEvalList, EndpointError> {
+    let metrics = state.metrics.clone();
+    let caches = state.caches(base_url).await;
+
+    metrics.cache_lookup("jobset_evals");
+
+    get_or_fetch(
+        &caches.jobset_eval_list_cache,
+        (base_url.clone(), jobset.clone()),
+        refresh,
+        async {
+            metrics.cache_miss("jobset_evals");
+
+            fetch_jobset_eval_list(
+                state.client.clone(),
+                base_url.clone(),
+                jobset,
+                metrics.clone(),
+                caches.jobset_eval_list_etags.clone(),
+            )
+            .await
+        },
+    )
+    .await
+}
+
+/// Checks each jobset's evaluations build by build
+async fn check_jobsets(
+    state: &AppState,
+    params: &RequestQuery,
+    base_url: &Url,
+    job_matcher: &Matcher,
+    jobsets: Vec<Jobset>,
+    timings: &mut Timings,
+) -> Result<Vec<(Jobset, JobsetStatus)>, EndpointError> {
+    let started = Instant::now();
+    let jobset_eval_lists: Vec<(Jobset, JobsetEvalList)> =
+        try_join_all(jobsets.into_iter().map(|jobset| {
+            get_jobset_eval_list(state, base_url, jobset.clone(), params.no_cache)
+                .map_ok(|list| (jobset, list))
+        }))
+        .await?;
+
+    timings.record("evals", started);
+
+    let started = Instant::now();
+    let passing = jobset_eval_lists
+        .iter()
+        .map(|(_, list)| {
+            check_list_passing(state, params, base_url.clone(), job_matcher.clone(), list)
+        })
+        .collect::<Vec<_>>();
+
+    // Kept in order so statuses line up with `jobset_eval_lists`
+    let jobset_statuses: Vec<JobsetStatus> = try_join_all(passing).await?;
+
+    timings.record("builds", started);
+
+    Ok(jobset_eval_lists
+        .into_iter()
+        .zip(jobset_statuses)
+        .map(|((jobset, list), status)| {
+            let status = JobsetStatus {
+                fetched_at: Some(list.fetched_at),
+                ..status
+            };
+
+            (jobset, status)
+        })
+        .collect())
+}
+
+/// Lists the jobsets a request applies to, across every project
+async fn matching_jobsets(
+    state: &AppState,
+    params: &RequestQuery,
+    base_url: &Url,
+    jobset_matcher: &Matcher,
+) -> Result<Vec<Jobset>, EndpointError> {
+    if let Some(jobset) = &params.only_jobset {
+        return Ok(vec![jobset.clone()]);
+    }
+
+    let caches = state.caches(base_url).await;
+
+    state.metrics.cache_lookup("projects");
+
+    let projects = get_or_fetch(
+        &caches.projects_cache,
+        cache_key(base_url, state.cache_key_ignores_query),
+        params.no_cache,
+        async {
+            state.metrics.cache_miss("projects");
+
+            fetch_projects(
+                state.client.clone(),
+                base_url.clone(),
+                state.metrics.clone(),
+                caches.projects_etags.clone(),
+            )
+            .await
+        },
+    )
+    .await?;
+
+    debug!(projects = projects.len(), "fetched projects");
+
+    let jobsets = projects
+        .iter()
+        .filter(|project| project.enabled || params.include_disabled)
+        .flat_map(|project| {
+            project.jobsets.iter().map(|jobset| Jobset {
+                project: project.name.clone(),
+                name: jobset.to_string(),
+            })
+        })
+        .filter(|x| jobset_matcher.is_match(&x.to_string()))
+        .collect::<Vec<_>>();
+
+    let jobsets = match params.include_disabled {
+        true => jobsets,
+        false => without_disabled_jobsets(state, params, base_url, &caches, jobsets).await,
+    };
+
+    debug!(jobsets = jobsets.len(), "matched jobsets");
+
+    // Each jobset costs Hydra an evaluation list and its builds
+    let max = state.max_jobsets(params.max_jobsets);
+
+    if jobsets.len() > max {
+        return Err(EndpointError::TooManyJobsets {
+            matched: jobsets.len(),
+            max,
+        });
+    }
+
+    Ok(jobsets)
+}
+
+/// Drops jobsets disabled in Hydra, which the project list doesn't mark
+///
+/// Only the projects of matched jobsets are listed. Projects whose listing
+/// fails keep every jobset, as there's no telling which are disabled.
+async fn without_disabled_jobsets(
+    state: &AppState,
+    params: &RequestQuery,
+    base_url: &Url,
+    caches: &HydraCaches,
+    jobsets: Vec<Jobset>,
+) -> Vec<Jobset> {
+    let projects = jobsets
+        .iter()
+        .map(|jobset| jobset.project.clone())
+        .collect::<HashSet<_>>();
+
+    let listings = join_all(projects.into_iter().map(|project| async move {
+        state.metrics.cache_lookup("project_jobsets");
+
+        let listing = get_or_fetch(
+            &caches.project_jobsets_cache,
+            (base_url.clone(), project.clone()),
+            params.no_cache,
+            async {
+                state.metrics.cache_miss("project_jobsets");
+
+                fetch_project_jobsets(
+                    state.client.clone(),
+                    base_url.clone(),
+                    project.clone(),
+                    state.metrics.clone(),
+                )
+                .await
+            },
+        )
+        .await;
+
+        (project, listing)
+    }))
+    .await;
+
+    let mut disabled = HashSet::new();
+
+    for (project, listing) in listings {
+        match listing {
+            Ok(listing) => {
+                disabled.extend(listing.into_iter().filter(|jobset| !jobset.enabled).map(
+                    |jobset| Jobset {
+                        project: project.clone(),
+                        name: jobset.name,
+                    },
+                ))
+            }
+            Err(error) => warn!(%error, project, "couldn't tell which jobsets are disabled"),
+        }
+    }
+
+    jobsets
+        .into_iter()
+        .filter(|jobset| !disabled.contains(jobset))
+        .collect()
+}
+
+/// Computes a badge, keeping how long each phase took if `debug` is set
+async fn badge(
+    params: RequestQuery,
+    state: AppState,
+) -> Result<EndpointResponse, ArcEndpointError> {
+    let debug = params.debug;
+    let mut timings = Timings::default();
+    let mut badge = compute_badge(params, state, &mut timings).await?;
+
+    if debug {
+        badge.timings = timings;
+    }
+
+    Ok(badge)
+}
+
+async fn compute_badge(
+    params: RequestQuery,
+    state: AppState,
+    timings: &mut Timings,
+) -> Result<EndpointResponse, ArcEndpointError> {
+    // Any other strategy skips past exactly the evaluations still in the queue
+    let params = match params.metric {
+        Metric::Status => params,
+        Metric::QueueDepth => RequestQuery {
+            eval_strategy: EvalStrategy::Latest,
+            ..params
+        },
+    };
+
+    let base_url = state.base_url(params.hydra_base_url.clone())?;
+    state.check_host(&base_url)?;
+
+    let client = state.client.clone();
+    let (jobset_matcher, job_matcher) = state.request_matchers(&params).await?;
+    let label = params
+        .label
+        .clone()
+        .unwrap_or_else(|| format!("{}:{}", params.jobsets(), params.jobs()));
+
+    let started = Instant::now();
+    let jobsets = matching_jobsets(&state, &params, &base_url, &jobset_matcher).await?;
+
+    timings.record("projects", started);
+
+    if jobsets.is_empty() {
+        return Ok(no_matches(label, "no matching jobsets", &params));
+    }
+
+    let jobset_statuses: Vec<(Jobset, JobsetStatus)> = match params.source {
+        Source::Builds => {
+            check_jobsets(&state, &params, &base_url, &job_matcher, jobsets, timings).await?
+        }
+        Source::Counters => {
+            let started = Instant::now();
+            let statuses = try_join_all(jobsets.into_iter().map(|jobset| {
+                fetch_jobset(
+                    client.clone(),
+                    base_url.clone(),
+                    jobset.clone(),
+                    state.metrics.clone(),
+                )
+                .map_ok(|counters| {
+                    (
+                        jobset,
+                        counters.status(params.min_finished_builds, params.max_fail_ratio),
+                    )
+                })
+            }))
+            .await?;
+
+            timings.record("counters", started);
+            statuses
+        }
+    };
+
+    if params.metric == Metric::QueueDepth {
+        let queued: usize = jobset_statuses
+            .iter()
+            .map(|(_, status)| status.counts.queued)
+            .sum();
+
+        // Reported as building while anything is queued, for the text format
+        let state = match queued {
+            0 => JobsetState::Passing,
+            _ => JobsetState::Building,
+        };
+
+        return Ok(EndpointResponse {
+            label,
+            message: format!("{queued} queued"),
+            color: Some(
+                match queued {
+                    0 => "brightgreen",
+                    _ => "blue",
+                }
+                .into(),
+            ),
+            cache_seconds: Some(params.cache_seconds),
+            named_logo: params.logo,
+            logo_color: params.logo_color,
+            state: Some(state),
+            ..Default::default()
+        });
+    }
+
+    // A jobset without the requested jobs says nothing about those that have
+    // them, so it's left out rather than dragging the badge down
+    let jobset_statuses = jobset_statuses
+        .into_iter()
+        .filter(|(_, status)| !status.matched_nothing())
+        .collect::<Vec<_>>();
+
+    if jobset_statuses.is_empty() {
+        return Ok(no_matches(label, "no matching jobs", &params));
+    }
+
+    // In worst mode only the single worst jobset is counted and named
+    let (jobset_statuses, worst_jobset) = match params.aggregate {
+        Aggregate::All => (
+            jobset_statuses
+                .into_iter()
+                .map(|(_, status)| status)
+                .collect::<Vec<_>>(),
+            None,
+        ),
+        Aggregate::Worst => {
+            // Ties go to the first jobset by name, so the one named is stable
+            let (jobset, status) = jobset_statuses
+                .into_iter()
+                .max_by(|(a, a_status), (b, b_status)| {
+                    a_status.state().cmp(&b_status.state()).then(b.cmp(a))
+                })
+                .expect("at least one jobset matched");
+
+            (vec![status], Some(jobset))
+        }
+    };
+
+    let mut state = jobset_statuses
+        .iter()
+        .map(JobsetStatus::state)
+        .max()
+        .unwrap_or(JobsetState::Passing);
+
+    if params.invert {
+        state = match state {
+            JobsetState::Passing => JobsetState::Failing,
+            JobsetState::Failing => JobsetState::Passing,
+            other => other,
+        };
+    }
+
+    let passing_builds: usize = jobset_statuses
+        .iter()
+        .map(|status| status.counts.passing)
+        .sum();
+    let total_builds: usize = jobset_statuses
+        .iter()
+        .map(|status| status.counts.total)
+        .sum();
+    let failed_builds: usize = jobset_statuses
+        .iter()
+        .map(|status| status.counts.failed)
+        .sum();
+    let skipped_builds: usize = jobset_statuses
+        .iter()
+        .map(|status| status.counts.skipped)
+        .sum();
+    let sampled_builds: usize = jobset_statuses
+        .iter()
+        .map(|status| status.counts.sampled)
+        .sum();
+    let unsampled_builds: usize = jobset_statuses
+        .iter()
+        .map(|status| status.counts.unsampled)
+        .sum();
+    let unreachable_builds: usize = jobset_statuses
+        .iter()
+        .map(|status| status.counts.unreachable)
+        .sum();
+    let mut failing_jobs = jobset_statuses
+        .iter()
+        .flat_map(|status| status.counts.failing_jobs.iter())
+        .collect::<Vec<_>>();
+    failing_jobs.sort_unstable();
+    failing_jobs.dedup();
+
+    let mut message = match (params.invert, state) {
+        (_, JobsetState::Building) => "building".into(),
+        (_, JobsetState::NoData) => "no data".into(),
+        (_, JobsetState::EvalError) => "eval error".into(),
+        (true, JobsetState::Passing) => "broken as expected".into(),
+        (true, JobsetState::Failing) => {
+            format!("unexpectedly passing ({passing_builds}/{total_builds})")
+        }
+        (false, JobsetState::Passing) if failed_builds > 0 => {
+            let percent = failed_builds * 100 / total_builds;
+            format!("{percent}% failing (under threshold)")
+        }
+        (false, JobsetState::Failing) if params.show_failing && !failing_jobs.is_empty() => {
+            failing_message(&failing_jobs)
+        }
+        (false, _) => format!("{passing_builds}/{total_builds} passing"),
+    };
+
+    let counted = !matches!(
+        state,
+        JobsetState::Building | JobsetState::NoData | JobsetState::EvalError
+    );
+
+    if skipped_builds > 0 && counted {
+        // Dependency failures are folded in with cancellations when ignored
+        let skipped = match params.ignore_dep_failures {
+            true => "skipped",
+            false => "cancelled",
+        };
+
+        message.push_str(&format!(", {skipped_builds} {skipped}"));
+    }
+
+    if unreachable_builds > 0 {
+        let builds = match unreachable_builds {
+            1 => "build",
+            _ => "builds",
+        };
+
+        message.push_str(&format!(", {unreachable_builds} {builds} unreachable"));
+    }
+
+    if unsampled_builds > 0 {
+        let available = sampled_builds + unsampled_builds;
+        message.push_str(&format!(" (sampled {sampled_builds} of {available})"));
+    }
+
+    if let Some(jobset) = worst_jobset {
+        message = format!("{jobset} {message}");
+    }
+
+    // The stalest result is the one that bounds how current the badge is
+    let fetched_at = jobset_statuses
+        .iter()
+        .filter_map(|status| status.fetched_at)
+        .min();
+
+    if params.show_age
+        && let Some(fetched_at) = fetched_at
+    {
+        message.push_str(&format!(" ({} ago)", format_age(fetched_at.elapsed())));
+    }
+
+    debug!(?state, passing_builds, total_builds, "decided badge state");
+
+    if total_builds == 0 && counted {
+        return Ok(no_matches(label, "no matching jobs", &params));
+    }
+
+    Ok(EndpointResponse {
+        label,
+        message,
+        is_error: matches!(state, JobsetState::Failing | JobsetState::EvalError),
+        color: Some(badge_color(state)),
+        cache_seconds: Some(params.cache_seconds),
+        named_logo: params.logo,
+        logo_color: params.logo_color,
+        state: Some(state),
+        ..Default::default()
+    })
+}
+
+/// Failure of POST /preload, which unlike badges reports errors by status
+#[derive(Serialize, Debug)]
+struct PreloadError {
+    error: String,
+}
+
+#[derive(Serialize, Debug)]
+struct PreloadResponse {
+    preloaded: usize,
+    /// Error messages for badges that couldn't be computed
+    errors: Vec<String>,
+}
+
+/// Computes each requested badge, discarding the result, so later badge
+/// requests are served from warm caches
+///
+/// Only routed when `ENABLE_PRELOAD` is set, as each badge costs Hydra work.
+async fn preload(
+    State(state): State<AppState>,
+    Json(badges): Json<Vec<RequestQuery>>,
+) -> Result<Json<PreloadResponse>, (StatusCode, Json<PreloadError>)> {
+    let max = state.max_preload_badges;
+
+    if badges.len() > max {
+        let error = format!("too many badges to preload ({}, max {max})", badges.len());
+
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, Json(PreloadError { error })));
+    }
+
+    Ok(Json(preload_badges(&state, badges).await))
+}
+
+/// Computes each preset badge, for `WARMUP_ON_START`
+async fn warmup(state: AppState) {
+    let badges = state.presets.values().cloned().collect();
+    let response = preload_badges(&state, badges).await;
+
+    for error in response.errors {
+        warn!(%error, "failed to warm up preset");
+    }
+}
+
+/// Computes up to `PRELOAD_CONCURRENCY` badges at a time
+async fn preload_badges(state: &AppState, badges: Vec<RequestQuery>) -> PreloadResponse {
+    let results = futures::stream::iter(badges)
+        .map(|params| {
+            tokio::time::timeout(state.deadline, badge(params, state.clone())).map(|result| {
+                result.unwrap_or_else(|_| Err(EndpointError::DeadlineExceeded.into()))
+            })
+        })
+        .buffer_unordered(state.preload_concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    let errors = results
+        .iter()
+        .filter_map(|result| result.as_ref().err())
+        .map(|error| error.to_string())
+        .collect::<Vec<_>>();
+
+    info!(
+        badges = results.len(),
+        errors = errors.len(),
+        "preloaded badges"
+    );
+
+    PreloadResponse {
+        preloaded: results.len() - errors.len(),
+        errors,
+    }
+}
+
+async fn metrics(State(state): State<AppState>) -> String {
+    state.metrics.render()
+}
+
+/// Sizes and hit counters of a single cache, for GET /debug/cache
+#[derive(Serialize, Debug)]
+struct CacheStats {
+    entry_count: u64,
+    weighted_size: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lookups: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    misses: Option<u64>,
+}
+
+impl CacheStats {
+    async fn new<K, V>(cache: &Cache<K, V>, counters: Option<(u64, u64)>) -> Self
+    where
+        K: std::hash::Hash + Eq + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+    {
+        // Flushes pending inserts and evictions so the counts are current
+        cache.run_pending_tasks().await;
+
+        CacheStats {
+            entry_count: cache.entry_count(),
+            weighted_size: cache.weighted_size(),
+            lookups: counters.map(|(lookups, _)| lookups),
+            misses: counters.map(|(_, misses)| misses),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct DebugCacheResponse {
+    /// What capacities are multiplied by, one unless auto-sizing
+    scale: u64,
+    projects: CacheStats,
+    jobset_evals: CacheStats,
+    builds: CacheStats,
+    failed_builds: CacheStats,
+}
+
+/// Only routed when `ENABLE_DEBUG_ENDPOINTS` is set
+async fn debug_cache(State(state): State<AppState>) -> Json<DebugCacheResponse> {
+    let metrics = &state.metrics;
+    let caches = state.hydra_caches.read().unwrap().clone();
+
+    Json(DebugCacheResponse {
+        scale: caches.scale,
+        projects: CacheStats::new(
+            &caches.projects_cache,
+            Some(metrics.cache_counts("projects")),
+        )
+        .await,
+        jobset_evals: CacheStats::new(
+            &caches.jobset_eval_list_cache,
+            Some(metrics.cache_counts("jobset_evals")),
+        )
+        .await,
+        builds: CacheStats::new(&caches.build_cache, Some(metrics.cache_counts("build"))).await,
+        failed_builds: CacheStats::new(&caches.failed_build_cache, None).await,
+    })
+}
+
+/// A matched build, for GET /debug/status
+#[derive(Serialize, Debug)]
+struct DebugBuild {
+    id: i32,
+    job: String,
+    finished: bool,
+    buildstatus: Option<BuildStatus>,
+}
+
+/// How a single jobset was judged, for GET /debug/status
+#[derive(Serialize, Debug)]
+struct DebugJobset {
+    jobset: String,
+    state: JobsetState,
+    /// The evaluation counted, or `None` when none had finished builds
+    evaluation: Option<i32>,
+    counts: BuildCounts,
+    builds: Vec<DebugBuild>,
+}
+
+#[derive(Serialize, Debug)]
+struct DebugStatusResponse {
+    jobsets: Vec<DebugJobset>,
+}
+
+/// Breaks a badge down into the jobsets and builds behind it, always reading
+/// builds rather than counters
+///
+/// Only routed when `ENABLE_DEBUG_ENDPOINTS` is set
+async fn debug_status(
+    BadgeQuery(params): BadgeQuery<RequestQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<DebugStatusResponse>, EndpointError> {
+    let base_url = state.base_url(params.hydra_base_url.clone())?;
+    state.check_host(&base_url)?;
+
+    let (jobset_matcher, job_matcher) = state.request_matchers(&params).await?;
+    let jobsets = matching_jobsets(&state, &params, &base_url, &jobset_matcher).await?;
+
+    let jobsets = jobsets.into_iter().map(|jobset| {
+        let (state, params, base_url, job_matcher) = (&state, &params, &base_url, &job_matcher);
+
+        async move {
+            let list =
+                get_jobset_eval_list(state, base_url, jobset.clone(), params.no_cache).await?;
+            let status =
+                check_list_passing(state, params, base_url.clone(), job_matcher.clone(), &list)
+                    .await?;
+
+            // Already cached by the check above, so this is cheap
+            let evaluation = list
+                .evals
+                .iter()
+                .find(|evaluation| Some(evaluation.id) == status.evaluation);
+            let ids = evaluation.map_or_else(Vec::new, |evaluation| {
+                sampled_builds(evaluation, state.max_builds(params.max_builds))
+            });
+            let builds = join_all(
+                ids.iter()
+                    .map(|id| get_build(state, base_url.clone(), *id, false)),
+            )
+            .await;
+
+            let builds = ids
+                .into_iter()
+                .zip(builds)
+                .filter_map(|(id, build)| build.ok().map(|build| (id, build)))
+                .filter(|(_, build)| job_matcher.is_match(&build.job))
+                .map(|(id, build)| DebugBuild {
+                    id,
+                    job: build.job,
+                    finished: build.finished == 1,
+                    buildstatus: build.buildstatus,
+                })
+                .collect();
+
+            Ok::<_, EndpointError>(DebugJobset {
+                jobset: jobset.to_string(),
+                state: status.state(),
+                evaluation: status.evaluation,
+                counts: status.counts,
+                builds,
+            })
+        }
+    });
+
+    Ok(Json(DebugStatusResponse {
+        jobsets: try_join_all(jobsets).await?,
+    }))
+}
+
+#[derive(Serialize, Debug)]
+struct HealthResponse {
+    status: &'static str,
+}
+
+async fn health() -> Json<HealthResponse> {
+    Json(HealthResponse { status: "ok" })
+}
+
+#[derive(Serialize, Debug)]
+struct VersionResponse {
+    version: &'static str,
+    commit: &'static str,
+    /// Unix timestamp of the build
+    built_at: &'static str,
+}
+
+async fn version() -> Json<VersionResponse> {
+    Json(VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("GIT_COMMIT"),
+        built_at: env!("BUILD_TIMESTAMP"),
+    })
+}
+
+#[derive(Serialize, Debug)]
+struct LegendStatus {
+    buildstatus: i32,
+    name: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+struct LegendState {
+    state: &'static str,
+    color: String,
+}
+
+#[derive(Serialize, Debug)]
+struct LegendResponse {
+    /// Hydra `buildstatus` codes we distinguish; any other counts as failed
+    build_statuses: Vec<LegendStatus>,
+    /// Badge states, best to worst
+    states: Vec<LegendState>,
+}
+
+async fn legend() -> Json<LegendResponse> {
+    let states = [
+        JobsetState::Passing,
+        JobsetState::NoData,
+        JobsetState::Building,
+        JobsetState::Failing,
+        JobsetState::EvalError,
+    ];
+
+    Json(LegendResponse {
+        build_statuses: BuildStatus::KNOWN
+            .into_iter()
+            .map(|code| LegendStatus {
+                buildstatus: code,
+                name: BuildStatus::from(code).name(),
+            })
+            .collect(),
+        states: states
+            .into_iter()
+            .map(|state| LegendState {
+                state: state.as_str(),
+                color: badge_color(state),
+            })
+            .collect(),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct ReadyQuery {
+    /// Falls back to `DEFAULT_HYDRA_BASE_URL`
+    #[serde(default, deserialize_with = "base_url")]
+    hydra_base_url: Option<Url>,
+}
+
+#[derive(Serialize, Debug)]
+struct ReadyResponse {
+    status: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+async fn ready(
+    Query(params): Query<ReadyQuery>,
+    State(state): State<AppState>,
+) -> (StatusCode, Json<ReadyResponse>) {
+    let base_url = match state.base_url(params.hydra_base_url) {
+        Ok(base_url) => base_url,
+        Err(error) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ReadyResponse {
+                    status: "invalid",
+                    error: Some(error.to_string()),
+                }),
+            );
+        }
+    };
+
+    if let Err(error) = state.check_host(&base_url) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(ReadyResponse {
+                status: "forbidden",
+                error: Some(error.to_string()),
+            }),
+        );
+    }
+
+    let result = state
+        .client
+        .request(Method::HEAD, base_url)
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    match result {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(ReadyResponse {
+                status: "ok",
+                error: None,
+            }),
+        ),
+        Err(error) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ReadyResponse {
+                status: "unavailable",
+                error: Some(error.to_string()),
+            }),
+        ),
+    }
+}
+
+/// Loads the JSON object of named badge presets pointed to by `PRESETS_FILE`
+fn load_presets() -> anyhow::Result<HashMap<String, RequestQuery>> {
+    let Ok(path) = std::env::var("PRESETS_FILE") else {
+        return Ok(HashMap::new());
+    };
+
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read PRESETS_FILE {path:?}"))?;
+    let presets: HashMap<String, RequestQuery> = serde_json::from_str(&contents)
+        .with_context(|| format!("failed to parse PRESETS_FILE {path:?}"))?;
+
+    info!("loaded {} badge presets", presets.len());
+
+    Ok(presets)
+}
+
+/// Resolves on Ctrl-C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("shutting down, waiting for in-flight requests");
+}
+
+impl AppState {
+    /// Builds the shared state from environment variables
+    fn from_env() -> anyhow::Result<Self> {
+        Self::with_caches(CacheConfig::from_env())
+    }
+
+    /// Builds the shared state with explicit cache sizes and lifetimes, the
+    /// rest still coming from environment variables
+    fn with_caches(caches: CacheConfig) -> anyhow::Result<Self> {
+        let user_agent = std::env::var("HYDRA_USER_AGENT")
+            .unwrap_or_else(|_| format!("hydra-shields-endpoint/{}", env!("CARGO_PKG_VERSION")));
+        let scale = caches
+            .autosize
+            .as_ref()
+            .map_or(1, |autosize| autosize.min_scale);
+        let extra_headers = Arc::new(extra_headers()?);
+        let allowed_hosts = std::env::var("ALLOWED_HYDRA_HOSTS").ok().map(|hosts| {
+            Arc::new(
+                hosts
+                    .split(',')
+                    .map(|host| host.trim().to_string())
+                    .filter(|host| !host.is_empty())
+                    .collect::<HashSet<_>>(),
+            )
+        });
+
+        Ok(AppState {
+            hydra_caches: Arc::new(RwLock::new(HydraCaches::new(&caches, scale))),
+            hosts_seen: Cache::builder()
+                .max_capacity(
+                    caches
+                        .autosize
+                        .as_ref()
+                        .map_or(0, |autosize| autosize.max_scale),
+                )
+                .time_to_live(
+                    caches
+                        .autosize
+                        .as_ref()
+                        .map_or(Duration::ZERO, |autosize| autosize.host_ttl),
+                )
+                .build(),
+            matchers: Cache::new(caches.matchers_capacity),
+            client: HydraClient {
+                inner: http_client(
+                    &user_agent,
+                    allowed_hosts.clone(),
+                    extra_headers.keys().cloned().collect(),
+                )?,
+                max_retries: env_or("HYDRA_MAX_RETRIES", 3),
+                auth_tokens: Arc::new(auth_tokens()),
+                extra_headers,
+                build_fetches: Arc::new(Semaphore::new(
+                    env_or("MAX_CONCURRENT_BUILD_FETCHES", 32).max(1),
+                )),
+                rate_limiter: rate_limiter().map(Arc::new),
+                rate_limit_wait: Duration::from_millis(env_or("HYDRA_RATE_LIMIT_WAIT_MS", 1_000)),
+                max_retry_after: Duration::from_secs(env_or("HYDRA_MAX_RETRY_AFTER_SECS", 10)),
+                max_response_bytes: env_or("HYDRA_MAX_RESPONSE_BYTES", 32 * 1024 * 1024),
+            },
+            metrics: Metrics::new(),
+            allowed_hosts,
+            deadline: Duration::from_millis(env_or("ENDPOINT_DEADLINE_MS", 60_000)),
+            presets: Arc::new(load_presets()?),
+            max_builds: env_or("MAX_BUILDS_PER_EVAL", 1000),
+            max_jobsets: env_or("MAX_JOBSETS_PER_REQUEST", 100),
+            max_preload_badges: env_or("MAX_PRELOAD_BADGES", 100),
+            preload_concurrency: env_or("PRELOAD_CONCURRENCY", 8).max(1),
+            default_base_url: std::env::var("DEFAULT_HYDRA_BASE_URL")
+                .ok()
+                .map(|url| {
+                    Url::parse(&url)
+                        .map(with_trailing_slash)
+                        .with_context(|| format!("invalid DEFAULT_HYDRA_BASE_URL {url:?}"))
+                })
+                .transpose()?,
+            inflight: Singleflight::new(),
+            cache_key_ignores_query: env_or("CACHE_KEY_IGNORE_QUERY", false),
+            max_label_length: env_or("MAX_LABEL_LENGTH", 64),
+            max_message_length: env_or("MAX_MESSAGE_LENGTH", 128),
+            request_log: RequestLog {
+                rate: env_or("REQUEST_LOG_SAMPLE_RATE", 100),
+                seen: Arc::new(AtomicU64::new(0)),
+            },
+            last_good: Cache::builder()
+                .max_capacity(caches.stale_badges_capacity)
+                .time_to_live(caches.stale_badge_ttl)
+                .build(),
+            cache_config: Arc::new(caches),
+        })
+    }
+}
+
+/// Sizes and lifetimes of every cache in [`AppState`]
+#[derive(Clone, Debug)]
+struct CacheConfig {
+    projects_capacity: u64,
+    projects_ttl: Duration,
+    jobset_evals_capacity: u64,
+    jobset_evals_ttl: Duration,
+    /// How long responses are kept for conditional refetches
+    etag_ttl: Duration,
+    builds_capacity: u64,
+    finished_build_ttl: Duration,
+    running_build_ttl: Duration,
+    failed_builds_capacity: u64,
+    failed_build_ttl: Duration,
+    matchers_capacity: u64,
+    stale_badges_capacity: u64,
+    stale_badge_ttl: Duration,
+    /// Share of each Hydra response cache TTL randomly shaved off per entry
+    jitter: f64,
+    /// Scales Hydra response cache capacities by hosts seen, when enabled
+    autosize: Option<AutoSize>,
+}
+
+/// Treats the Hydra response cache capacities as per host, multiplying them
+/// by the number of hosts seen rounded up to a power of two
+#[derive(Clone, Debug)]
+struct AutoSize {
+    min_scale: u64,
+    max_scale: u64,
+    /// How long a host counts towards the scale after its last request
+    host_ttl: Duration,
+    /// How often the scale is recomputed
+    interval: Duration,
+}
+
+impl AutoSize {
+    fn scale(&self, hosts: u64) -> u64 {
+        hosts
+            .max(1)
+            .next_power_of_two()
+            .clamp(self.min_scale, self.max_scale)
+    }
+}
+
+impl CacheConfig {
+    fn from_env() -> Self {
+        let secs = |name, default| Duration::from_secs(env_or(name, default));
+
+        CacheConfig {
+            projects_capacity: env_or("PROJECTS_CACHE_CAPACITY", 100),
+            projects_ttl: secs("PROJECTS_CACHE_TTL_SECS", 600),
+            jobset_evals_capacity: env_or("JOBSET_EVAL_CACHE_CAPACITY", 100),
+            jobset_evals_ttl: secs("JOBSET_EVAL_CACHE_TTL_SECS", 60),
+            etag_ttl: secs("ETAG_CACHE_TTL_SECS", 86_400),
+            builds_capacity: env_or("BUILD_CACHE_CAPACITY", 1000),
+            finished_build_ttl: secs("BUILD_CACHE_TTL_SECS", 3600),
+            running_build_ttl: secs("RUNNING_BUILD_CACHE_TTL_SECS", 10),
+            failed_builds_capacity: env_or("FAILED_BUILD_CACHE_CAPACITY", 1000),
+            failed_build_ttl: secs("FAILED_BUILD_CACHE_TTL_SECS", 5),
+            matchers_capacity: env_or("MATCHER_CACHE_CAPACITY", 1000),
+            stale_badges_capacity: env_or("STALE_BADGE_CACHE_CAPACITY", 10_000),
+            stale_badge_ttl: secs("STALE_BADGE_TTL_SECS", 3600),
+            jitter: env_or("CACHE_TTL_JITTER", 0.1),
+            autosize: env_or("CACHE_AUTOSIZE", false).then(|| {
+                let min_scale = env_or("CACHE_AUTOSIZE_MIN_SCALE", 1).max(1);
+
+                AutoSize {
+                    min_scale,
+                    max_scale: env_or("CACHE_AUTOSIZE_MAX_SCALE", 16).max(min_scale),
+                    host_ttl: secs("CACHE_AUTOSIZE_HOST_TTL_SECS", 86_400),
+                    interval: secs("CACHE_AUTOSIZE_INTERVAL_SECS", 60),
+                }
+            }),
+        }
+    }
+}
+
+fn router(state: AppState) -> Router {
+    let mut app = Router::new()
+        .route("/", get(endpoint))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/version", get(version))
+        .route("/legend", get(legend))
+        .route("/metrics", get(metrics))
+        .route("/preset/{name}", get(preset))
+        .route("/jobset/{project}/{jobset}", get(jobset))
+        .route("/build/{id}", get(build));
+
+    if env_or("ENABLE_PRELOAD", false) {
+        info!("preload endpoint enabled");
+
+        app = app.route("/preload", post(preload));
+    }
+
+    if env_or("ENABLE_DEBUG_ENDPOINTS", false) {
+        info!("debug endpoints enabled");
+
+        app = app
+            .route("/debug/cache", get(debug_cache))
+            .route("/debug/status", get(debug_status));
+    }
+
+    // Only applied when the client sends `Accept-Encoding`, and skipped for
+    // responses too small to benefit, like most badges
+    app.layer(CompressionLayer::new())
+        .layer(cors())
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(RequestIds::new()))
+        .with_state(state)
+}
+
+/// Generates short `x-request-id`s, unless the client sent one, so error
+/// badges can be matched up with logs
+#[derive(Clone)]
+struct RequestIds(Arc<AtomicU32>);
+
+impl RequestIds {
+    /// Starts from the clock so IDs are unlikely to repeat across restarts
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.subsec_nanos() ^ elapsed.as_secs() as u32)
+            .unwrap_or_default();
+
+        RequestIds(Arc::new(AtomicU32::new(seed)))
+    }
+}
+
+impl MakeRequestId for RequestIds {
+    fn make_request_id<B>(&mut self, _request: &axum::http::Request<B>) -> Option<RequestId> {
+        let id = self.0.fetch_add(1, Ordering::Relaxed);
+
+        Some(RequestId::new(format!("{id:08x}").parse().unwrap()))
+    }
+}
+
+/// Allows browsers to GET badges from the origins in `CORS_ALLOWED_ORIGINS`,
+/// or from anywhere when unset
+fn cors() -> CorsLayer {
+    let origins = match std::env::var("CORS_ALLOWED_ORIGINS") {
+        Ok(origins) => AllowOrigin::list(
+            origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .filter_map(|origin| match origin.parse() {
+                    Ok(origin) => Some(origin),
+                    Err(_) => {
+                        warn!("ignoring invalid CORS_ALLOWED_ORIGINS entry {origin:?}");
+                        None
+                    }
+                }),
+        ),
+        Err(_) => AllowOrigin::any(),
+    };
+
+    CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::HEAD])
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let log_format = std::env::var("LOG_FORMAT").unwrap_or_default();
+
+    // Text stays the default, as it's far easier to read locally
+    match log_format.as_str() {
+        "json" => tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(filter)
+            .init(),
+        _ => tracing_subscriber::fmt().with_env_filter(filter).init(),
+    }
+
+    if !matches!(log_format.as_str(), "" | "json" | "text") {
+        warn!("invalid value {log_format:?} for LOG_FORMAT, using text");
+    }
+
+    let state = AppState::from_env()?;
+
+    if let Some(autosize) = &state.cache_config.autosize {
+        let state = state.clone();
+        let mut interval = tokio::time::interval(autosize.interval);
+
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                state.autosize().await;
+            }
+        });
+    }
+
+    if state.client.rate_limiter.is_some() {
+        let client = state.client.clone();
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                client.prune_rate_limiter();
+            }
+        });
+    }
+
+    // Runs alongside the server, so a slow or failing Hydra never holds up
+    // startup
+    if env_or("WARMUP_ON_START", false) {
+        tokio::spawn(warmup(state.clone()));
+    }
+
+    let app = router(state);
+
+    let bind_addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0:3000".into());
+    let bind_addr: SocketAddr = bind_addr
+        .parse()
+        .with_context(|| format!("invalid BIND_ADDR {bind_addr:?}"))?;
+
+    let listener = tokio::net::TcpListener::bind(bind_addr)
+        .await
+        .with_context(|| format!("failed to bind to {bind_addr}"))?;
+
+    info!("listening on {}", listener.local_addr()?);
+
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+    let server = axum::serve(listener, app).with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(());
+    });
+    let grace = Duration::from_secs(env_or("SHUTDOWN_TIMEOUT_SECS", 30));
+
+    tokio::select! {
+        result = server => result?,
+        _ = async {
+            let _ = shutdown_rx.await;
+            tokio::time::sleep(grace).await;
+        } => warn!("in-flight requests did not finish within {grace:?}, exiting"),
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    Ok(())
 }
@@ -0,0 +1,146 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::EndpointError;
+
+/// How `jobsets` and `jobs` patterns are interpreted
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    #[default]
+    Glob,
+    Regex,
+}
+
+impl MatchMode {
+    /// A pattern matching any name
+    pub fn match_all(self) -> &'static str {
+        match self {
+            Self::Glob => "*",
+            Self::Regex => ".*",
+        }
+    }
+}
+
+/// How much of a job name a `jobs` pattern has to cover
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum JobMatch {
+    /// The pattern matches the whole name
+    #[default]
+    Exact,
+    /// The pattern matches the start of the name, e.g. `hello` matches
+    /// `hello.x86_64-linux`
+    Prefix,
+    /// The pattern matches anywhere in the name
+    Contains,
+}
+
+impl JobMatch {
+    /// Rewrites `pattern` so an exact match of the result is a match of
+    /// `pattern` under this mode
+    pub fn widen(self, pattern: &str, mode: MatchMode) -> String {
+        let (before, after) = match (self, mode) {
+            (Self::Exact, _) => return pattern.to_string(),
+            (Self::Prefix, MatchMode::Glob) => ("", "*"),
+            (Self::Contains, MatchMode::Glob) => ("*", "*"),
+            (Self::Prefix, MatchMode::Regex) => ("(?:", ").*"),
+            (Self::Contains, MatchMode::Regex) => (".*(?:", ").*"),
+        };
+
+        match mode {
+            MatchMode::Glob => split_globs(pattern)
+                .map(|glob| format!("{before}{glob}{after}"))
+                .collect::<Vec<_>>()
+                .join(","),
+            MatchMode::Regex => format!("{before}{pattern}{after}"),
+        }
+    }
+}
+
+/// A compiled `jobsets` or `jobs` pattern
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    /// Any of a comma separated list of globs
+    Glob(GlobSet),
+    Regex(Regex),
+}
+
+impl Matcher {
+    pub fn new(pattern: &str, mode: MatchMode) -> Result<Self, EndpointError> {
+        match mode {
+            MatchMode::Glob => {
+                let mut builder = GlobSetBuilder::new();
+
+                for pattern in split_globs(pattern) {
+                    builder.add(Glob::new(pattern).map_err(|error| {
+                        EndpointError::InvalidPattern(format!(
+                            "invalid glob pattern {pattern:?}: {}",
+                            error.kind()
+                        ))
+                    })?);
+                }
+
+                builder
+                    .build()
+                    .map(Self::Glob)
+                    .map_err(|error| EndpointError::InvalidPattern(error.to_string()))
+            }
+            // Anchored so a regex, like a glob, has to match the whole name
+            MatchMode::Regex => Regex::new(&format!("^(?:{pattern})$"))
+                .map(Self::Regex)
+                .map_err(|error| EndpointError::InvalidPattern(error.to_string())),
+        }
+    }
+
+    pub fn is_match(&self, haystack: &str) -> bool {
+        match self {
+            Self::Glob(matcher) => matcher.is_match(haystack),
+            Self::Regex(regex) => regex.is_match(haystack),
+        }
+    }
+}
+
+/// Escapes a comma separated list of names so each matches only itself
+///
+/// In glob mode that's `?`, `*`, `[`, `]`, `{` and `}`; in regex mode every
+/// regex metacharacter, including the `.` common in names like
+/// `release-23.05`. A regex is one name rather than a list, so commas are only
+/// split on in glob mode.
+pub fn literal(names: &str, mode: MatchMode) -> String {
+    match mode {
+        MatchMode::Glob => names
+            .split(',')
+            .map(|name| globset::escape(name.trim()))
+            .collect::<Vec<_>>()
+            .join(","),
+        MatchMode::Regex => regex::escape(names),
+    }
+}
+
+/// Splits a glob list on commas, leaving those inside `{a,b}` alternations
+fn split_globs(patterns: &str) -> impl Iterator<Item = &str> {
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut globs = Vec::new();
+
+    for (index, char) in patterns.char_indices() {
+        match char {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                globs.push(&patterns[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+
+    globs.push(&patterns[start..]);
+
+    globs
+        .into_iter()
+        .map(str::trim)
+        .filter(|glob| !glob.is_empty())
+}
@@ -0,0 +1,99 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+/// Prometheus metrics exported at GET /metrics
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounter,
+    pub errors: IntCounterVec,
+    cache_lookups: IntCounterVec,
+    cache_misses: IntCounterVec,
+    pub fetch_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests = IntCounter::new(
+            "hydra_shields_requests_total",
+            "Total badge endpoint requests",
+        )
+        .unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new(
+                "hydra_shields_errors_total",
+                "Badge endpoint errors by variant",
+            ),
+            &["variant"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "hydra_shields_cache_lookups_total",
+                "Cache lookups by cache",
+            ),
+            &["cache"],
+        )
+        .unwrap();
+        let cache_misses = IntCounterVec::new(
+            Opts::new("hydra_shields_cache_misses_total", "Cache misses by cache"),
+            &["cache"],
+        )
+        .unwrap();
+        let fetch_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "hydra_shields_fetch_duration_seconds",
+                "Latency of upstream Hydra fetches",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry.register(Box::new(cache_misses.clone())).unwrap();
+        registry.register(Box::new(fetch_duration.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            errors,
+            cache_lookups,
+            cache_misses,
+            fetch_duration,
+        }
+    }
+
+    /// Records a lookup against `cache`; pair with [`Metrics::cache_miss`] in
+    /// the cache's init future so hits can be derived
+    pub fn cache_lookup(&self, cache: &str) {
+        self.cache_lookups.with_label_values(&[cache]).inc();
+    }
+
+    pub fn cache_miss(&self, cache: &str) {
+        self.cache_misses.with_label_values(&[cache]).inc();
+    }
+
+    /// Lookups and misses recorded so far against `cache`
+    pub fn cache_counts(&self, cache: &str) -> (u64, u64) {
+        (
+            self.cache_lookups.with_label_values(&[cache]).get(),
+            self.cache_misses.with_label_values(&[cache]).get(),
+        )
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+
+        String::from_utf8(buffer).unwrap()
+    }
+}
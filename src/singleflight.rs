@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use futures::FutureExt;
+use futures::future::{BoxFuture, Shared, WeakShared};
+
+/// Shares one in-flight computation between concurrent callers with the same
+/// key, so a burst of identical requests only does the work once
+pub struct Singleflight<K, V> {
+    inflight: Arc<Mutex<HashMap<K, WeakShared<BoxFuture<'static, V>>>>>,
+}
+
+impl<K, V> Clone for Singleflight<K, V> {
+    fn clone(&self) -> Self {
+        Singleflight {
+            inflight: self.inflight.clone(),
+        }
+    }
+}
+
+impl<K, V> Singleflight<K, V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Singleflight {
+            inflight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Awaits the computation already running for `key`, or starts `work`
+    ///
+    /// Held weakly, so a computation every caller gave up on is dropped rather
+    /// than kept around for the next one.
+    pub fn run(
+        &self,
+        key: K,
+        work: impl Future<Output = V> + Send + 'static,
+    ) -> Shared<BoxFuture<'static, V>> {
+        let mut inflight = self.inflight.lock().unwrap();
+
+        if let Some(shared) = inflight.get(&key).and_then(WeakShared::upgrade) {
+            return shared;
+        }
+
+        let cleanup = self.inflight.clone();
+        let shared = {
+            let key = key.clone();
+
+            async move {
+                let value = work.await;
+                cleanup.lock().unwrap().remove(&key);
+                value
+            }
+            .boxed()
+            .shared()
+        };
+
+        inflight.retain(|_, weak| weak.upgrade().is_some());
+        inflight.insert(
+            key,
+            shared.downgrade().expect("shared future has not completed"),
+        );
+
+        shared
+    }
+}
@@ -0,0 +1,49 @@
+/// Rough average glyph width of 11px Verdana, which the badge text is set in
+const CHAR_WIDTH: usize = 7;
+
+/// Horizontal padding on either side of each half of the badge
+const PADDING: usize = 6;
+
+/// Resolves a shields.io color name or hex code to an SVG fill
+fn fill(color: &str) -> String {
+    match color {
+        "brightgreen" | "success" => "#4c1".into(),
+        "green" => "#97ca00".into(),
+        "yellowgreen" => "#a4a61d".into(),
+        "yellow" => "#dfb317".into(),
+        "orange" | "important" => "#fe7d37".into(),
+        "red" | "critical" => "#e05d44".into(),
+        "blue" | "informational" => "#007ec6".into(),
+        "lightgrey" | "lightgray" | "inactive" => "#9f9f9f".into(),
+        hex if hex.chars().all(|c| c.is_ascii_hexdigit()) => format!("#{hex}"),
+        other => escape(other),
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn text_width(text: &str) -> usize {
+    text.chars().count() * CHAR_WIDTH + 2 * PADDING
+}
+
+/// Renders a flat-style badge like shields.io's default
+pub fn render(label: &str, message: &str, color: &str) -> String {
+    let label_width = text_width(label);
+    let message_width = text_width(message);
+    let width = label_width + message_width;
+    let label_x = label_width / 2;
+    let message_x = label_width + message_width / 2;
+    let fill = fill(color);
+    let label = escape(label);
+    let message = escape(message);
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}"><title>{label}: {message}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{message_width}" height="20" fill="{fill}"/><rect width="{width}" height="20" fill="url(#s)"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11"><text x="{label_x}" y="15" fill="#010101" fill-opacity=".3">{label}</text><text x="{label_x}" y="14">{label}</text><text x="{message_x}" y="15" fill="#010101" fill-opacity=".3">{message}</text><text x="{message_x}" y="14">{message}</text></g></svg>"##
+    )
+}
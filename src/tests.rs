@@ -0,0 +1,1235 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use axum::Json;
+use axum::extract::State;
+use reqwest::Url;
+
+use crate::{
+    AppState, AutoSize, BuildStatus, CacheConfig, HydraClient, RequestQuery, cache_key, next_page,
+    preload, redirect_policy, router, warmup, with_trailing_slash,
+};
+
+/// A build served by [`FakeHydra`]
+struct FakeBuild {
+    id: i32,
+    job: &'static str,
+    finished: bool,
+    buildstatus: Option<i32>,
+}
+
+fn succeeded(id: i32, job: &'static str) -> FakeBuild {
+    FakeBuild {
+        id,
+        job,
+        finished: true,
+        buildstatus: Some(0),
+    }
+}
+
+fn failed(id: i32, job: &'static str) -> FakeBuild {
+    FakeBuild {
+        id,
+        job,
+        finished: true,
+        buildstatus: Some(1),
+    }
+}
+
+fn dep_failed(id: i32, job: &'static str) -> FakeBuild {
+    FakeBuild {
+        id,
+        job,
+        finished: true,
+        buildstatus: Some(2),
+    }
+}
+
+fn running(id: i32, job: &'static str) -> FakeBuild {
+    FakeBuild {
+        id,
+        job,
+        finished: false,
+        buildstatus: None,
+    }
+}
+
+/// A Hydra with a single project `proj` whose jobset `main` has one
+/// evaluation containing `builds`
+struct FakeHydra {
+    server: MockServer,
+    prefix: &'static str,
+}
+
+impl FakeHydra {
+    async fn start(builds: &[FakeBuild]) -> Self {
+        Self::start_at("", builds).await
+    }
+
+    /// Serves Hydra under `prefix`, like a deployment behind a reverse proxy
+    async fn start_at(prefix: &'static str, builds: &[FakeBuild]) -> Self {
+        let server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path(format!("{prefix}/")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "name": "proj", "jobsets": ["main"] },
+            ])))
+            .mount(&server)
+            .await;
+
+        let ids = builds.iter().map(|build| build.id).collect::<Vec<_>>();
+
+        Mock::given(method("GET"))
+            .and(path(format!("{prefix}/jobset/proj/main/evals")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "evals": [{ "builds": ids }],
+            })))
+            .mount(&server)
+            .await;
+
+        for build in builds {
+            Mock::given(method("GET"))
+                .and(path(format!("{prefix}/build/{}", build.id)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "job": build.job,
+                    "finished": build.finished as i32,
+                    "buildstatus": build.buildstatus,
+                })))
+                .mount(&server)
+                .await;
+        }
+
+        FakeHydra { server, prefix }
+    }
+
+    fn url(&self) -> String {
+        format!("{}{}/", self.server.uri(), self.prefix)
+    }
+}
+
+/// Requests `path_and_query` from a fresh in-process server
+async fn get(path_and_query: &str) -> Value {
+    get_response(path_and_query).await.json().await.unwrap()
+}
+
+async fn get_response(path_and_query: &str) -> reqwest::Response {
+    let addr = serve().await;
+
+    reqwest::get(format!("http://{addr}{path_and_query}"))
+        .await
+        .unwrap()
+}
+
+/// Starts an in-process server, for tests spanning several requests
+async fn serve() -> SocketAddr {
+    serve_with(AppState::from_env().unwrap()).await
+}
+
+/// Like [`serve`], but with caches configured by the test
+async fn serve_with_caches(caches: CacheConfig) -> SocketAddr {
+    serve_with(AppState::with_caches(caches).unwrap()).await
+}
+
+async fn serve_with(state: AppState) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = router(state);
+
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    addr
+}
+
+/// Requests a badge pointed at `hydra`
+async fn badge(hydra: &FakeHydra, query: &str) -> Value {
+    get(&format!("/?hydra_base_url={}&{query}", hydra.url())).await
+}
+
+async fn assert_badge(builds: &[FakeBuild], query: &str, message: &str, color: &str) {
+    let hydra = FakeHydra::start(builds).await;
+    let badge = badge(&hydra, query).await;
+
+    assert_eq!(badge["message"], message, "{badge}");
+    assert_eq!(badge["color"], color, "{badge}");
+}
+
+#[tokio::test]
+async fn passing() {
+    assert_badge(
+        &[succeeded(1, "hello"), succeeded(2, "world")],
+        "jobsets=proj:main&jobs=*",
+        "2/2 passing",
+        "brightgreen",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn failing() {
+    assert_badge(
+        &[succeeded(1, "hello"), failed(2, "world")],
+        "jobsets=proj:main&jobs=*",
+        "1/2 passing",
+        "red",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn queued() {
+    assert_badge(
+        &[succeeded(1, "hello"), running(2, "world")],
+        "jobsets=proj:main&jobs=*",
+        "building",
+        "yellow",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn filters_jobs() {
+    assert_badge(
+        &[succeeded(1, "hello"), failed(2, "world")],
+        "jobsets=proj:*&jobs=hello",
+        "1/1 passing",
+        "brightgreen",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn no_matching_jobsets() {
+    assert_badge(
+        &[succeeded(1, "hello")],
+        "jobsets=other:*&jobs=*",
+        "no matching jobsets",
+        "red",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn too_few_finished_builds() {
+    assert_badge(
+        &[succeeded(1, "hello"), failed(2, "world")],
+        "jobsets=proj:main&jobs=*&min_finished_builds=3",
+        "no data",
+        "lightgrey",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn missing_base_url() {
+    let badge = get("/?jobsets=*&jobs=*").await;
+
+    assert_eq!(badge["label"], "Missing Base URL", "{badge}");
+    assert_eq!(badge["isError"], true, "{badge}");
+    assert!(
+        badge["message"].as_str().unwrap().contains("[req="),
+        "{badge}"
+    );
+}
+
+#[test]
+fn base_url_without_trailing_slash() {
+    let url = with_trailing_slash(Url::parse("https://example.com/hydra").unwrap());
+
+    assert_eq!(
+        url.join("build/1").unwrap().as_str(),
+        "https://example.com/hydra/build/1"
+    );
+}
+
+#[test]
+fn base_url_with_trailing_slash() {
+    let url = with_trailing_slash(Url::parse("https://example.com/hydra/").unwrap());
+
+    assert_eq!(
+        url.join("build/1").unwrap().as_str(),
+        "https://example.com/hydra/build/1"
+    );
+}
+
+#[test]
+fn cache_key_drops_fragment() {
+    let url = Url::parse("https://example.com/?a=1#top").unwrap();
+
+    assert_eq!(cache_key(&url, false).as_str(), "https://example.com/?a=1");
+    assert_eq!(cache_key(&url, true).as_str(), "https://example.com/");
+}
+
+#[tokio::test]
+async fn failures_under_threshold() {
+    assert_badge(
+        &[
+            succeeded(1, "a"),
+            succeeded(2, "b"),
+            succeeded(3, "c"),
+            failed(4, "d"),
+        ],
+        "jobsets=proj:main&jobs=*&max_fail_ratio=0.25",
+        "25% failing (under threshold)",
+        "brightgreen",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn non_json_response() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw("<html></html>", "text/html"))
+        .mount(&server)
+        .await;
+
+    let badge = get(&format!(
+        "/?hydra_base_url={}/&jobsets=*&jobs=*",
+        server.uri()
+    ))
+    .await;
+
+    assert!(
+        badge["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Hydra returned non-JSON response (content-type: text/html)"),
+        "{badge}"
+    );
+}
+
+#[tokio::test]
+async fn show_age() {
+    assert_badge(
+        &[succeeded(1, "hello")],
+        "jobsets=proj:main&jobs=*&show_age=true",
+        "1/1 passing (0s ago)",
+        "brightgreen",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn subpath_base_url() {
+    let hydra = FakeHydra::start_at("/hydra", &[succeeded(1, "hello"), failed(2, "world")]).await;
+    let badge = badge(&hydra, "jobsets=proj:main&jobs=*").await;
+
+    assert_eq!(badge["message"], "1/2 passing", "{badge}");
+}
+
+#[tokio::test]
+async fn subpath_base_url_without_trailing_slash() {
+    let hydra = FakeHydra::start_at("/hydra", &[succeeded(1, "hello")]).await;
+    let url = hydra.url();
+    let badge = get(&format!(
+        "/?hydra_base_url={}&jobsets=proj:main&jobs=*",
+        url.trim_end_matches('/')
+    ))
+    .await;
+
+    assert_eq!(badge["message"], "1/1 passing", "{badge}");
+}
+
+#[tokio::test]
+async fn ignore_dep_failures() {
+    assert_badge(
+        &[succeeded(1, "hello"), dep_failed(2, "world")],
+        "jobsets=proj:main&jobs=*&ignore_dep_failures=true",
+        "1/2 passing, 1 skipped",
+        "brightgreen",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn unreachable_builds() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+
+    // Build 2 has no mock, so fetching it 404s
+    Mock::given(method("GET"))
+        .and(path("/jobset/proj/main/evals"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "evals": [{ "builds": [1, 2] }],
+        })))
+        .with_priority(1)
+        .mount(&hydra.server)
+        .await;
+
+    let badge = badge(&hydra, "jobsets=proj:main&jobs=*").await;
+
+    assert_eq!(
+        badge["message"], "1/1 passing, 1 build unreachable",
+        "{badge}"
+    );
+}
+
+#[tokio::test]
+async fn rate_limited_by_hydra() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+        .mount(&server)
+        .await;
+
+    let badge = get(&format!(
+        "/?hydra_base_url={}/&jobsets=*&jobs=*",
+        server.uri()
+    ))
+    .await;
+
+    assert_eq!(badge["label"], "Rate Limited", "{badge}");
+    assert!(
+        badge["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("rate limited by Hydra"),
+        "{badge}"
+    );
+}
+
+#[tokio::test]
+async fn eval_window() {
+    let hydra = FakeHydra::start(&[failed(1, "hello"), running(2, "hello")]).await;
+
+    // The newest evaluation is still building, the one before it failed
+    Mock::given(method("GET"))
+        .and(path("/jobset/proj/main/evals"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "evals": [
+                { "id": 2, "timestamp": 2, "builds": [2] },
+                { "id": 1, "timestamp": 1, "builds": [1] },
+            ],
+        })))
+        .with_priority(1)
+        .mount(&hydra.server)
+        .await;
+
+    let latest = badge(&hydra, "jobsets=proj:main&jobs=*&eval_window=1").await;
+    assert_eq!(latest["message"], "building", "{latest}");
+
+    let recent = badge(&hydra, "jobsets=proj:main&jobs=*&eval_window=2").await;
+    assert_eq!(recent["message"], "0/1 passing", "{recent}");
+}
+
+#[tokio::test]
+async fn json_content_type() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    let success = get_response(&format!(
+        "/?hydra_base_url={}&jobsets=proj:main&jobs=*",
+        hydra.url()
+    ))
+    .await;
+    let error = get_response("/?jobsets=*&jobs=*").await;
+
+    for response in [success, error] {
+        assert_eq!(response.headers()["content-type"], "application/json");
+    }
+}
+
+#[tokio::test]
+async fn job_match_prefix() {
+    assert_badge(
+        &[
+            succeeded(1, "hello.x86_64-linux"),
+            failed(2, "world.x86_64-linux"),
+        ],
+        "jobsets=proj:main&jobs=hello&job_match=prefix",
+        "1/1 passing",
+        "brightgreen",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn all_queued() {
+    assert_badge(
+        &[running(1, "hello"), running(2, "world")],
+        "jobsets=proj:main&jobs=*",
+        "building",
+        "yellow",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn stale_while_error() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    let addr = serve().await;
+    let url = format!(
+        "http://{addr}/?hydra_base_url={}&jobsets=proj:main&jobs=*&no_cache=true",
+        hydra.url()
+    );
+
+    let fresh: Value = reqwest::get(&url).await.unwrap().json().await.unwrap();
+    assert_eq!(fresh["message"], "1/1 passing", "{fresh}");
+
+    // Every Hydra path now 404s
+    hydra.server.reset().await;
+
+    let stale: Value = reqwest::get(&url).await.unwrap().json().await.unwrap();
+    assert_eq!(stale["message"], "1/1 passing (stale)", "{stale}");
+}
+
+#[tokio::test]
+async fn exact_jobs() {
+    assert_badge(
+        &[succeeded(1, "hello[1]"), failed(2, "hello1")],
+        "jobsets=proj:main&jobs=hello%5B1%5D&exact_jobs=true",
+        "1/1 passing",
+        "brightgreen",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn show_failing() {
+    assert_badge(
+        &[
+            succeeded(1, "a"),
+            failed(2, "b"),
+            failed(3, "c"),
+            failed(4, "d"),
+            failed(5, "e"),
+            failed(6, "f"),
+        ],
+        "jobsets=proj:main&jobs=*&show_failing=true",
+        "failing: b, c, d (+2 more)",
+        "red",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn omitted_patterns_match_all() {
+    assert_badge(
+        &[succeeded(1, "hello"), succeeded(2, "world")],
+        "",
+        "2/2 passing",
+        "brightgreen",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn eval_error() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+
+    Mock::given(method("GET"))
+        .and(path("/jobset/proj/main/evals"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "evals": [
+                { "id": 2, "timestamp": 2, "builds": [], "evaluationerror": { "errormsg": "oops" } },
+                { "id": 1, "timestamp": 1, "builds": [1] },
+            ],
+        })))
+        .with_priority(1)
+        .mount(&hydra.server)
+        .await;
+
+    let badge = badge(&hydra, "jobsets=proj:main&jobs=*").await;
+
+    assert_eq!(badge["message"], "eval error", "{badge}");
+    assert_eq!(badge["color"], "red", "{badge}");
+}
+
+#[tokio::test]
+async fn accept_text_plain() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello"), failed(2, "world")]).await;
+    let addr = serve().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{addr}/?hydra_base_url={}", hydra.url()))
+        .header("Accept", "text/plain")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.text().await.unwrap(), "failing");
+}
+
+/// Requests the badge for `hydra` twice, finishing its running build between
+/// the two, and returns the second message
+async fn message_after_build_finishes(caches: CacheConfig) -> Value {
+    let hydra = FakeHydra::start(&[running(1, "hello")]).await;
+    let addr = serve_with_caches(caches).await;
+    let url = format!("http://{addr}/?hydra_base_url={}", hydra.url());
+
+    let before: Value = reqwest::get(&url).await.unwrap().json().await.unwrap();
+    assert_eq!(before["message"], "building", "{before}");
+
+    Mock::given(method("GET"))
+        .and(path("/build/1"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "job": "hello",
+            "finished": 1,
+            "buildstatus": 0,
+        })))
+        .with_priority(1)
+        .mount(&hydra.server)
+        .await;
+
+    let after: Value = reqwest::get(&url).await.unwrap().json().await.unwrap();
+    after["message"].clone()
+}
+
+#[tokio::test]
+async fn running_build_cached_until_expiry() {
+    let caches = CacheConfig {
+        jitter: 0.0,
+        ..CacheConfig::from_env()
+    };
+
+    assert_eq!(message_after_build_finishes(caches).await, "building");
+}
+
+#[tokio::test]
+async fn running_build_refetched_after_expiry() {
+    let caches = CacheConfig {
+        running_build_ttl: Duration::ZERO,
+        jitter: 0.0,
+        ..CacheConfig::from_env()
+    };
+
+    assert_eq!(message_after_build_finishes(caches).await, "1/1 passing");
+}
+
+#[tokio::test]
+async fn too_many_jobsets() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    let badge = badge(&hydra, "max_jobsets=0").await;
+
+    assert_eq!(badge["isError"], true, "{badge}");
+    assert!(
+        badge["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("too many jobsets matched (1, max 0)"),
+        "{badge}"
+    );
+}
+
+#[tokio::test]
+async fn queue_depth() {
+    assert_badge(
+        &[succeeded(1, "a"), running(2, "b"), running(3, "c")],
+        "metric=queue_depth",
+        "2 queued",
+        "blue",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn invalid_glob() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    let badge = badge(&hydra, "jobs=%5B").await;
+
+    assert_eq!(badge["isError"], true, "{badge}");
+    assert!(
+        badge["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid glob pattern \"[\""),
+        "{badge}"
+    );
+}
+
+#[tokio::test]
+async fn invalid_query() {
+    let response = get_response("/?max_fail_ratio=2").await;
+
+    assert_eq!(response.status(), 200);
+
+    let badge: Value = response.json().await.unwrap();
+
+    assert_eq!(badge["label"], "Invalid Query", "{badge}");
+    assert_eq!(badge["isError"], true, "{badge}");
+    assert!(
+        badge["message"].as_str().unwrap().contains("[req="),
+        "{badge}"
+    );
+}
+
+#[tokio::test]
+async fn disabled_projects() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+            { "name": "proj", "jobsets": ["main"], "enabled": 0 },
+        ])))
+        .with_priority(1)
+        .mount(&hydra.server)
+        .await;
+
+    let skipped = badge(&hydra, "").await;
+    assert_eq!(skipped["message"], "no matching jobsets", "{skipped}");
+
+    let included = badge(&hydra, "include_disabled=true").await;
+    assert_eq!(included["message"], "1/1 passing", "{included}");
+}
+
+#[tokio::test]
+async fn disabled_jobsets() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    add_other_jobset(&hydra, failed(2, "hello")).await;
+
+    Mock::given(method("GET"))
+        .and(path("/api/jobsets"))
+        .and(query_param("project", "proj"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+            { "name": "main", "enabled": 1 },
+            { "name": "other", "enabled": 0 },
+        ])))
+        .mount(&hydra.server)
+        .await;
+
+    let skipped = badge(&hydra, "").await;
+    assert_eq!(skipped["message"], "1/1 passing", "{skipped}");
+
+    let included = badge(&hydra, "include_disabled=true").await;
+    assert_eq!(included["message"], "1/2 passing", "{included}");
+}
+
+#[tokio::test]
+async fn cache_autosize() {
+    let state = AppState::with_caches(CacheConfig {
+        autosize: Some(AutoSize {
+            min_scale: 1,
+            max_scale: 4,
+            host_ttl: Duration::from_secs(60),
+            interval: Duration::from_secs(60),
+        }),
+        ..CacheConfig::from_env()
+    })
+    .unwrap();
+    let scale = |state: &AppState| state.hydra_caches.read().unwrap().scale;
+
+    for host in ["a", "b", "b", "c"] {
+        let url = Url::parse(&format!("https://{host}.example/")).unwrap();
+        state.caches(&url).await;
+    }
+
+    assert_eq!(scale(&state), 1);
+    state.autosize().await;
+    assert_eq!(scale(&state), 4);
+
+    for host in ["d", "e"] {
+        let url = Url::parse(&format!("https://{host}.example/")).unwrap();
+        state.caches(&url).await;
+    }
+
+    state.autosize().await;
+    assert_eq!(scale(&state), 4, "capped at max_scale");
+}
+
+#[tokio::test]
+async fn debug_server_timing() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    let timing = |response: &reqwest::Response| {
+        response
+            .headers()
+            .get("server-timing")
+            .map(|value| value.to_str().unwrap().to_string())
+    };
+
+    let plain = get_response(&format!("/?hydra_base_url={}", hydra.url())).await;
+    assert_eq!(timing(&plain), None);
+
+    let debug = get_response(&format!("/?hydra_base_url={}&debug=true", hydra.url())).await;
+    let phases: Vec<_> = timing(&debug)
+        .unwrap()
+        .split(", ")
+        .map(|phase| phase.split_once(";dur=").unwrap().0.to_string())
+        .collect();
+    assert_eq!(phases, ["projects", "evals", "builds", "total"]);
+
+    let badge: Value = debug.json().await.unwrap();
+    assert_eq!(badge["message"], "1/1 passing", "{badge}");
+}
+
+#[tokio::test]
+async fn warmup_presets() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    let preset: RequestQuery =
+        serde_json::from_value(json!({ "hydra_base_url": hydra.url() })).unwrap();
+    let unreachable: RequestQuery =
+        serde_json::from_value(json!({ "hydra_base_url": "http://127.0.0.1:1/" })).unwrap();
+    let state = AppState {
+        presets: Arc::new(HashMap::from([
+            ("hello".to_string(), preset),
+            ("unreachable".to_string(), unreachable),
+        ])),
+        ..AppState::from_env().unwrap()
+    };
+
+    warmup(state.clone()).await;
+
+    let caches = state.hydra_caches.read().unwrap().clone();
+    caches.build_cache.run_pending_tasks().await;
+    assert_eq!(caches.build_cache.entry_count(), 1);
+}
+
+#[tokio::test]
+async fn truncated_label_and_message() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    let addr = serve_with(AppState {
+        max_label_length: 8,
+        max_message_length: 6,
+        ..AppState::from_env().unwrap()
+    })
+    .await;
+    let get = |query: String| async move {
+        reqwest::get(format!("http://{addr}/?{query}"))
+            .await
+            .unwrap()
+            .json::<Value>()
+            .await
+            .unwrap()
+    };
+
+    let badge = get(format!("hydra_base_url={}&label=a-long-label", hydra.url())).await;
+    assert_eq!(badge["label"], "a-long-…", "{badge}");
+    assert_eq!(badge["message"], "1/1 p…", "{badge}");
+
+    let error = get(format!("hydra_base_url={}&jobsets=[", hydra.url())).await;
+    let message = error["message"].as_str().unwrap();
+    assert!(message.contains("… [req="), "{error}");
+}
+
+#[tokio::test]
+async fn counters_failed_without_total() {
+    let hydra = FakeHydra::start(&[]).await;
+
+    Mock::given(method("GET"))
+        .and(path("/project/proj/jobset/main"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "nrfailed": 1 })))
+        .mount(&hydra.server)
+        .await;
+
+    let failing = badge(&hydra, "source=counters").await;
+    assert_eq!(failing["message"], "0/1 passing", "{failing}");
+
+    let tolerated = badge(&hydra, "source=counters&max_fail_ratio=1").await;
+    assert_eq!(
+        tolerated["message"], "100% failing (under threshold)",
+        "{tolerated}"
+    );
+}
+
+/// Adds a jobset "other" to `proj`, whose evaluation has just `build`
+async fn add_other_jobset(hydra: &FakeHydra, build: FakeBuild) {
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+            { "name": "proj", "jobsets": ["main", "other"] },
+        ])))
+        .with_priority(1)
+        .mount(&hydra.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/jobset/proj/other/evals"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "evals": [{ "builds": [build.id] }],
+        })))
+        .mount(&hydra.server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path(format!("/build/{}", build.id)))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "job": build.job,
+            "finished": build.finished as i32,
+            "buildstatus": build.buildstatus,
+        })))
+        .mount(&hydra.server)
+        .await;
+}
+
+#[tokio::test]
+async fn jobset_without_matching_jobs() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    add_other_jobset(&hydra, succeeded(2, "world")).await;
+
+    let hello = badge(&hydra, "jobs=hello").await;
+    assert_eq!(hello["message"], "1/1 passing", "{hello}");
+    assert_eq!(hello["color"], "brightgreen", "{hello}");
+    assert_eq!(hello["isError"], false, "{hello}");
+
+    let neither = badge(&hydra, "jobs=missing").await;
+    assert_eq!(neither["message"], "no matching jobs", "{neither}");
+}
+
+#[tokio::test]
+async fn all_skipped() {
+    let cancelled = |id| FakeBuild {
+        id,
+        job: "hello",
+        finished: true,
+        buildstatus: Some(4),
+    };
+
+    assert_badge(&[cancelled(1), cancelled(2)], "", "no data", "lightgrey").await;
+    assert_badge(
+        &[dep_failed(1, "hello")],
+        "ignore_dep_failures=true",
+        "no data",
+        "lightgrey",
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn worst_ties_broken_by_name() {
+    let hydra = FakeHydra::start(&[failed(1, "hello")]).await;
+    add_other_jobset(&hydra, failed(2, "hello")).await;
+
+    for _ in 0..5 {
+        let worst = badge(&hydra, "aggregate=worst&no_cache=true").await;
+        assert_eq!(worst["message"], "proj:main 0/1 passing", "{worst}");
+    }
+}
+
+#[tokio::test]
+async fn text_format_for_builds_and_queue_depth() {
+    let hydra = FakeHydra::start(&[succeeded(1, "a"), failed(2, "b"), running(3, "c")]).await;
+    let addr = serve().await;
+    let text = |path_and_query: String| async move {
+        reqwest::get(format!("http://{addr}{path_and_query}"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    };
+    let base_url = hydra.url();
+
+    for (build, expected) in [(1, "passing"), (2, "failing"), (3, "building")] {
+        let status = text(format!(
+            "/build/{build}?hydra_base_url={base_url}&format=text"
+        ))
+        .await;
+        assert_eq!(status, expected, "build {build}");
+    }
+
+    let queue = text(format!(
+        "/?hydra_base_url={base_url}&metric=queue_depth&format=text"
+    ))
+    .await;
+    assert_eq!(queue, "building");
+}
+
+#[test]
+fn next_page_stays_on_origin() {
+    let url = Url::parse("https://hydra.example/").unwrap();
+    let link = |target: &str| {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::LINK,
+            format!("<{target}>; rel=\"next\"").parse().unwrap(),
+        );
+        headers
+    };
+
+    assert_eq!(
+        next_page(&url, &link("/?page=2")).map(String::from),
+        Some("https://hydra.example/?page=2".into())
+    );
+    assert_eq!(next_page(&url, &link("http://169.254.169.254/")), None);
+}
+
+#[tokio::test]
+async fn redirects_stay_on_allowed_hosts() {
+    let server = MockServer::start().await;
+    let port = server.address().port();
+
+    Mock::given(method("GET"))
+        .and(path("/elsewhere"))
+        .respond_with(
+            ResponseTemplate::new(302)
+                .insert_header("Location", format!("http://localhost:{port}/")),
+        )
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/here"))
+        .respond_with(ResponseTemplate::new(302).insert_header("Location", "/"))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let client = reqwest::Client::builder()
+        .redirect(redirect_policy(
+            Some(Arc::new(["127.0.0.1".to_string()].into())),
+            HashSet::new(),
+        ))
+        .build()
+        .unwrap();
+
+    let allowed = client.get(format!("{}/here", server.uri())).send().await;
+    assert_eq!(allowed.unwrap().status(), 200);
+
+    let forbidden = client
+        .get(format!("{}/elsewhere", server.uri()))
+        .send()
+        .await;
+    assert!(forbidden.unwrap_err().is_redirect());
+}
+
+#[tokio::test]
+async fn preload_limits() {
+    let hydra = FakeHydra::start(&[succeeded(1, "hello")]).await;
+    let state = AppState {
+        max_preload_badges: 2,
+        preload_concurrency: 1,
+        ..AppState::from_env().unwrap()
+    };
+    let badges = |count| {
+        let badge: RequestQuery =
+            serde_json::from_value(json!({ "hydra_base_url": hydra.url() })).unwrap();
+        vec![badge; count]
+    };
+
+    let preloaded = preload(State(state.clone()), Json(badges(2)))
+        .await
+        .unwrap();
+    assert_eq!(preloaded.preloaded, 2);
+
+    let (status, error) = preload(State(state), Json(badges(3))).await.unwrap_err();
+    assert_eq!(status, reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(error.error, "too many badges to preload (3, max 2)");
+}
+
+/// A client for calling Hydra directly, retrying once
+fn hydra_client() -> HydraClient {
+    HydraClient {
+        inner: reqwest::Client::new(),
+        max_retries: 1,
+        auth_tokens: Arc::new(HashMap::new()),
+        extra_headers: Arc::new(HashMap::new()),
+        build_fetches: Arc::new(tokio::sync::Semaphore::new(32)),
+        rate_limiter: None,
+        rate_limit_wait: Duration::from_secs(1),
+        max_retry_after: Duration::from_secs(2),
+        max_response_bytes: 1024,
+    }
+}
+
+#[tokio::test]
+async fn build_fetch_permits_released_while_retrying() {
+    let slow = MockServer::start().await;
+    let fast = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(503).insert_header("Retry-After", "2"))
+        .mount(&slow)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&fast)
+        .await;
+
+    let client = HydraClient {
+        build_fetches: Arc::new(tokio::sync::Semaphore::new(1)),
+        ..hydra_client()
+    };
+
+    let retrying = tokio::spawn({
+        let client = client.clone();
+        let url = Url::parse(&format!("{}/build/1", slow.uri())).unwrap();
+        async move { client.get_build(url).await.map(|_| ()) }
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let started = Instant::now();
+    let url = Url::parse(&format!("{}/build/1", fast.uri())).unwrap();
+    let (_response, _permit) = client.get_build(url).await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(
+        client.build_fetches.available_permits(),
+        0,
+        "held until the body is read"
+    );
+
+    retrying.abort();
+}
+
+#[tokio::test]
+async fn extra_headers_only_sent_to_their_host() {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+
+    let headers = |host: &str| {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("cf-access-token", "secret".parse().unwrap());
+        Arc::new(HashMap::from([(host.to_string(), headers)]))
+    };
+    let url = Url::parse(&server.uri()).unwrap();
+
+    for (host, expected) in [("127.0.0.1", Some("secret")), ("hydra.example", None)] {
+        let client = HydraClient {
+            extra_headers: headers(host),
+            ..hydra_client()
+        };
+        client.get(url.clone()).await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let sent = requests.last().unwrap().headers.get("cf-access-token");
+        assert_eq!(
+            sent.map(|value| value.to_str().unwrap()),
+            expected,
+            "{host}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn no_evaluations_with_min_finished_builds() {
+    let hydra = FakeHydra::start(&[]).await;
+
+    Mock::given(method("GET"))
+        .and(path("/jobset/proj/main/evals"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "evals": [] })))
+        .with_priority(1)
+        .mount(&hydra.server)
+        .await;
+
+    let badge = badge(&hydra, "min_finished_builds=1").await;
+    assert_eq!(badge["message"], "no data", "{badge}");
+    assert_eq!(badge["color"], "lightgrey", "{badge}");
+}
+
+#[tokio::test]
+async fn rate_limiter_pruned() {
+    let quota = governor::Quota::per_second(std::num::NonZeroU32::new(1000).unwrap());
+    let client = HydraClient {
+        rate_limiter: Some(Arc::new(governor::RateLimiter::keyed(quota))),
+        ..hydra_client()
+    };
+    let rate_limiter = client.rate_limiter.as_ref().unwrap();
+
+    for host in ["a.example", "b.example"] {
+        let url = Url::parse(&format!("https://{host}/")).unwrap();
+        client.throttle(&url).await.unwrap();
+    }
+    assert_eq!(rate_limiter.len(), 2);
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    client.prune_rate_limiter();
+    assert_eq!(rate_limiter.len(), 0);
+}
+
+#[test]
+fn build_status_serialized_as_code() {
+    for code in BuildStatus::KNOWN.into_iter().chain([9]) {
+        let expected = match code {
+            6 => 1,
+            code => code,
+        };
+
+        assert_eq!(
+            serde_json::to_value(BuildStatus::from(code)).unwrap(),
+            json!(expected)
+        );
+    }
+}
+
+/// Starts a server that reads at most 64 bytes of each Hydra response
+async fn serve_with_small_responses() -> SocketAddr {
+    let state = AppState::from_env().unwrap();
+
+    serve_with(AppState {
+        client: HydraClient {
+            max_response_bytes: 64,
+            ..state.client.clone()
+        },
+        ..state
+    })
+    .await
+}
+
+async fn assert_response_too_large(addr: SocketAddr, hydra_url: &str) {
+    let badge: Value = reqwest::get(format!("http://{addr}/?hydra_base_url={hydra_url}"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(badge["isError"], true, "{badge}");
+    assert!(
+        badge["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Hydra response exceeded 64 bytes"),
+        "{badge}"
+    );
+}
+
+#[tokio::test]
+async fn response_too_large_by_length() {
+    let hydra = FakeHydra::start(&[]).await;
+    let projects = (0..10)
+        .map(|project| json!({ "name": format!("project-{project}"), "jobsets": ["main"] }))
+        .collect::<Vec<_>>();
+
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(projects))
+        .with_priority(1)
+        .mount(&hydra.server)
+        .await;
+
+    assert_response_too_large(serve_with_small_responses().await, &hydra.url()).await;
+}
+
+#[tokio::test]
+async fn response_too_large_chunked() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // wiremock always sends a `Content-Length`, so this Hydra streams its
+    // body in chunks without one
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hydra_url = format!("http://{}/", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = socket.read(&mut request).await;
+
+            let chunk = format!("[{}]", r#"{"name":"proj","jobsets":[]},"#.repeat(2));
+            let mut response = "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+                transfer-encoding: chunked\r\nconnection: close\r\n\r\n"
+                .to_string();
+            for _ in 0..4 {
+                response.push_str(&format!("{:x}\r\n{chunk}\r\n", chunk.len()));
+            }
+            response.push_str("0\r\n\r\n");
+
+            let _ = socket.write_all(response.as_bytes()).await;
+        }
+    });
+
+    assert_response_too_large(serve_with_small_responses().await, &hydra_url).await;
+}